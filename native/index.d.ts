@@ -1,73 +1,214 @@
-import {ISpotifyOptions, ISpotifyConnectOptions, ESpotifyConnectDeviceType, ESpotifyVolumeCtrl} from '../src/types';
+import {ISpotifyOptions, ISpotifyConnectOptions, ISpotifyDiscoveryOptions, ESpotifyConnectDeviceType, ESpotifyVolumeCtrl, ESpotifyConnectError, ESpotifyAuthType, ESpotifyConnectCommand, ISpotifyState, ISpotifyLoadOptions, ESpotifyCommandError, ISpotifyCommandResult, ISpotifyQueue, ESpotifyRepeatMode, ISpotifyPlayerConfig, ISpotifyReplayGain, ISpotifyAudioFormat} from '../src/types';
 
-interface IAudioDataEvent {
+// Called once the player confirms the command
+type TCommandCallback = (error: (Error & {code: ESpotifyCommandError}) | null, result: ISpotifyCommandResult) => void
+
+// Frames written to the audio stream before this event, or for audio data the offset of its first frame
+interface INativeEvent {
+  sampleOffset: number
+}
+
+interface IAudioDataEvent extends INativeEvent {
   name: 'audio-data',
   data: Buffer
 }
 
-interface IStartedEvent {
+// Audio written into `Spotify.audioRing`, both in frames of the output format
+interface IAudioRingEvent extends INativeEvent {
+  name: 'audio-ring',
+  offset: number,
+  frames: number
+}
+
+// Sent on the audio channel ahead of the first chunk
+interface IFormatEvent extends INativeEvent, ISpotifyAudioFormat {
+  name: 'format'
+}
+
+interface IStartedEvent extends INativeEvent {
   name: 'started',
   trackId: string,
+  playRequestId: number,
   positionMs: number
 }
 
-interface IStoppedEvent {
+interface IStoppedEvent extends INativeEvent {
   name: 'stopped',
-  trackId: string
+  trackId: string,
+  playRequestId: number
 }
 
-interface IChangedEvent {
+interface IChangedEvent extends INativeEvent {
   name: 'changed',
   newTrackId: string,
   oldTrackId: string
 }
 
-interface ILoadingEvent {
+interface ILoadingEvent extends INativeEvent {
   name: 'loading',
   trackId: string,
+  playRequestId: number,
   positionMs: number
 }
 
-interface IPlayingEvent {
+interface IPlayingEvent extends INativeEvent {
   name: 'playing',
   trackId: string,
+  playRequestId: number,
   positionMs: number,
-  durationMs: number
+  durationMs: number,
+  // null without normalisation, or while the gain isn't known yet. A 'replay-gain' event follows once it is
+  replayGain: ISpotifyReplayGain | null
 }
 
-interface IPausedEvent {
+interface IReplayGainEvent extends INativeEvent {
+  name: 'replay-gain',
+  trackId: string,
+  playRequestId: number,
+  replayGain: ISpotifyReplayGain
+}
+
+interface IPausedEvent extends INativeEvent {
   name: 'paused',
   trackId: string,
+  playRequestId: number,
   positionMs: number,
   durationMs: number
 }
 
-interface IEndOfTrackEvent {
+interface IEndOfTrackEvent extends INativeEvent {
   name: 'end-of-track',
-  trackId: string
+  trackId: string,
+  playRequestId: number
 }
 
-interface IVolumeSetEvent {
+interface IVolumeSetEvent extends INativeEvent {
   name: 'volume-set',
   volume: number
 }
 
-interface ITimeToPreloadNextTrackEvent {
+interface ITimeToPreloadNextTrackEvent extends INativeEvent {
   name: 'time-to-preload-next-track',
-  trackId: string
+  trackId: string,
+  playRequestId: number
 }
 
-interface IUnavailableEvent {
+interface IUnavailableEvent extends INativeEvent {
   name: 'unavailable',
+  trackId: string,
+  playRequestId: number
+}
+
+interface ICredentialsEvent extends INativeEvent {
+  name: 'credentials',
+  username: string,
+  authType: ESpotifyAuthType,
+  authData: string
+}
+
+interface ISessionLostEvent extends INativeEvent {
+  name: 'session-lost'
+}
+
+interface IReconnectingEvent extends INativeEvent {
+  name: 'reconnecting',
+  attempt: number,
+  delayMs: number
+}
+
+interface IReconnectedEvent extends INativeEvent {
+  name: 'reconnected'
+}
+
+interface IUserConnectedEvent extends INativeEvent {
+  name: 'user-connected',
+  username: string
+}
+
+interface IPositionEvent extends INativeEvent {
+  name: 'position',
+  trackId: string,
+  positionMs: number,
+  durationMs: number
+}
+
+interface IQueueChangedEvent extends INativeEvent, ISpotifyQueue {
+  name: 'queue-changed'
+}
+
+interface IPreloadedEvent extends INativeEvent {
+  name: 'preloaded',
   trackId: string
 }
 
-type TNativeSpotifyEvent = IAudioDataEvent | IStartedEvent | IStoppedEvent | IChangedEvent | ILoadingEvent | IPlayingEvent | IPausedEvent | IEndOfTrackEvent | IVolumeSetEvent | ITimeToPreloadNextTrackEvent | IUnavailableEvent;
+type TNativeSpotifyEvent = IReplayGainEvent | IPreloadedEvent | IQueueChangedEvent | IPositionEvent | IUserConnectedEvent | ISessionLostEvent | IReconnectingEvent | IReconnectedEvent | ICredentialsEvent | IStartedEvent | IStoppedEvent | IChangedEvent | ILoadingEvent | IPlayingEvent | IPausedEvent | IEndOfTrackEvent | IVolumeSetEvent | ITimeToPreloadNextTrackEvent | IUnavailableEvent;
+
+export class AccessToken {
+  getToken(): string
+  getExpiry(): number
+  getScope(): string[]
+}
 
 export class Spotify {
-  constructor(options: ISpotifyOptions)
-  play(trackId: string)
+  // Of the audio data, outputSampleRate or 44100
+  readonly sampleRate: number
+  readonly format: ISpotifyAudioFormat
+  // Only with ring delivery
+  readonly audioRing?: ArrayBuffer
+  // Takes a base62 track id, a spotify:track: or spotify:episode: URI, or an open.spotify.com link.
+  // Returns the play request id, which the events of this load carry
+  play(trackId: string, options?: ISpotifyLoadOptions, callback?: TCommandCallback): number
+  // Transport for direct playback, these throw unless a track is playing or paused (stop only needs one loaded), or while Connect is on the output
+  stop(callback?: TCommandCallback)
+  pause(callback?: TCommandCallback)
+  resume(callback?: TCommandCallback)
+  toggle(callback?: TCommandCallback)
+  seek(positionMs: number, callback?: TCommandCallback)
+  seekBy(deltaMs: number, callback?: TCommandCallback)
+  // 0-1
+  seekFraction(fraction: number, callback?: TCommandCallback)
+  restart(callback?: TCommandCallback)
+  // Starts fetching the track in the background, 'preloaded' follows once the whole file is fetched
+  preload(trackId: string)
+  // Queue for direct playback, advances on its own at the end of each track
+  enqueue(trackIds: string[])
+  insertNext(trackIds: string[])
+  removeFromQueue(index: number)
+  moveInQueue(from: number, to: number)
+  clearQueue()
+  // Shuffles the upcoming entries, turning it off goes back to the order they were queued in
+  setShuffle(shuffle: boolean)
+  setRepeat(repeat: ESpotifyRepeatMode)
+  getQueue(): ISpotifyQueue
+  next(callback?: TCommandCallback)
+  previous(callback?: TCommandCallback)
+  getToken(clientId: string, scopes: string, callback: (token?: AccessToken) => void)
   enableConnect(options: ISpotifyConnectOptions)
   disableConnect()
-  poll(callback: (error: Error | null, event: TNativeSpotifyEvent | null) => void)
+  connectCommand(command: ESpotifyConnectCommand)
+  setVolume(volume: number)
+  // Options left out keep their value, the direct player picks them up with its next load
+  setPlayerConfig(config: ISpotifyPlayerConfig)
+  // Before the volumeCtrl curve, like 'volume-set'
+  getVolume(): number
+  getState(): ISpotifyState
+  // Required once listening, the listen callbacks keep the instance and the event loop alive until then.
+  // Such an instance is never garbage collected, so nothing cleans it up otherwise
+  shutdown()
+  listen(callback: (event: TNativeSpotifyEvent) => void)
+  listenAudio(callback: (event: IAudioDataEvent | IAudioRingEvent | IFormatEvent) => void)
+  // Frames read by the consumer, releases the decoder with consumer pacing and frees them in the ring
+  acknowledgeAudio(frames: number)
+  // Drains up to maxEvents (default 256) queued events, empty array on timeout
+  poll(callback: (error: Error | null, events: TNativeSpotifyEvent[]) => void, maxEvents?: number)
+  pollAudio(callback: (error: Error | null, events: (IAudioDataEvent | IAudioRingEvent | IFormatEvent)[]) => void, maxEvents?: number)
+}
+
+export function connect(options: ISpotifyOptions, callback: (error: (Error & {code: ESpotifyConnectError}) | null, spotify: Spotify) => void): void
+// Calls back once a phone connects, or with a STOPPED error after `stop()`
+export function discover(options: ISpotifyDiscoveryOptions, callback: (error: (Error & {code: ESpotifyConnectError}) | null, spotify: Spotify) => void): Discovery
+
+export class Discovery {
+  // Stops announcing the device unless someone connected already
+  stop()
 }
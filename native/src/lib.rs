@@ -6,47 +6,680 @@ extern crate librespot;
 extern crate log;
 extern crate futures;
 extern crate simple_logging;
+extern crate base64;
 
 use log::{ LevelFilter };
 use std::env;
 
 use neon::context::Context;
+use neon::event::EventHandler;
 use neon::prelude::*;
 
 mod lib {
     pub mod player;
     pub mod token;
     pub mod events;
+    pub mod session;
+    pub mod mixer;
+    pub mod discovery;
+    pub mod pacing;
+    pub mod audio;
+    pub mod state;
+    pub mod commands;
+    pub mod queue;
+    pub mod uri;
+    pub mod replay_gain;
+    pub mod resample;
+    pub mod format;
+    pub mod errors;
 }
 
-use std::sync::{Arc};
-use lib::events::{EventEmitterTask};
-use lib::player::SpotifyPlayer;
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::thread;
+use lib::events::{Event, EventEmitterTask, DEFAULT_POLL_BATCH, queue_to_js, format_to_js};
+use lib::player::{SpotifyPlayer, PlayerOptions, PlayerConfigUpdate, LoadOptions, ConnectCommand};
 use lib::token::{ AccessToken, JsAccessToken };
+use lib::session::{ self, SessionError };
+use lib::discovery::Discovery;
+use lib::pacing::{ self, Pacing };
+use lib::audio::AudioDelivery;
+use lib::resample::SUPPORTED_SAMPLE_RATES;
+use lib::format::{ OutputFormat, SampleFormat, ChannelLayout, Channels };
+use lib::commands::{ self, CommandWaiter };
+use lib::errors::error_to_js;
+use lib::queue::RepeatMode;
+use lib::uri::parse_media_id;
 use std::str::FromStr;
+use std::path::PathBuf;
+use std::time::Duration;
 use librespot::playback::config::Bitrate;
-use librespot::core::config::{DeviceType, VolumeCtrl};
+use librespot::core::authentication::Credentials;
+use librespot::core::cache::Cache;
+use librespot::core::config::{ConnectConfig, DeviceType, SessionConfig, VolumeCtrl};
+use librespot::core::spotify_id::SpotifyId;
+use librespot::protocol::authentication::AuthenticationType;
+
+const NOT_CONNECTED: &str = "Spotify session is not connected";
+const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct Spotify {
-    player: SpotifyPlayer
+    player: Option<SpotifyPlayer>,
+    listening: bool,
+    listening_audio: bool
+}
+
+impl Spotify {
+    fn player(&mut self) -> Result<&mut SpotifyPlayer, &'static str> {
+        self.player.as_mut().ok_or(NOT_CONNECTED)
+    }
+
+    fn is_listening(&self, audio: bool) -> bool {
+        if audio { self.listening_audio } else { self.listening }
+    }
+
+    fn set_listening(&mut self, audio: bool) {
+        if audio {
+            self.listening_audio = true;
+        } else {
+            self.listening = true;
+        }
+    }
+}
+
+// Connects the session on the libuv thread pool, so the Node event loop keeps running during the handshake
+pub struct ConnectTask {
+    credentials: Credentials,
+    options: PlayerOptions,
+    cache_dir: String
+}
+
+impl Task for ConnectTask {
+    type Output = SpotifyPlayer;
+    type Error = SessionError;
+    type JsEvent = JsValue;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let cache = Cache::new(PathBuf::from(&self.cache_dir), true);
+
+        let connection = session::connect(SessionConfig::default(), self.credentials.clone(), Some(cache))?;
+
+        let player = SpotifyPlayer::new(connection, self.credentials.clone(), self.cache_dir.clone(), self.options.clone());
+
+        // Session::connect stores the reusable credentials it got back from the access point in the cache
+        if let Some(credentials) = Cache::new(PathBuf::from(&self.cache_dir), true).credentials() {
+            player.emit(Event::Credentials { credentials });
+        }
+
+        Ok(player)
+    }
+
+    fn complete(
+        self,
+        mut cx: TaskContext,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<Self::JsEvent> {
+        match result {
+            Ok(player) => spotify_instance(&mut cx, player),
+            Err(e) => {
+                let err = error_to_js(&mut cx, &e)?;
+
+                cx.throw(err)
+            }
+        }
+    }
+}
+
+// Zeroconf discovery that is still waiting for a phone on the LAN, returned by `discover`.
+// The login takes it over once someone connects, `stop` shuts it down before that.
+pub struct PendingDiscovery {
+    discovery: Arc<Mutex<Option<Discovery>>>
+}
+
+// Waits for a phone to hand over credentials, then logs in with them and enables Connect.
+// Runs on its own thread, so waiting for someone to connect doesn't occupy the libuv thread pool.
+fn connect_discovered(
+    discovery: Arc<Mutex<Option<Discovery>>>,
+    credentials_rx: mpsc::Receiver<Credentials>,
+    session_config: SessionConfig,
+    connect_config: ConnectConfig,
+    options: PlayerOptions,
+    cache_dir: String
+) -> Result<SpotifyPlayer, SessionError> {
+    // Disconnects once discovery is stopped
+    let credentials = credentials_rx.recv().map_err(|_| SessionError::Stopped)?;
+
+    // None when stopped in the meantime
+    let discovery = discovery.lock().unwrap().take().ok_or(SessionError::Stopped)?;
+
+    let username = credentials.username.clone();
+
+    // Session::connect persists the reusable credentials to the cache
+    let cache = Cache::new(PathBuf::from(&cache_dir), true);
+
+    let connection = session::connect(session_config, credentials.clone(), Some(cache))?;
+
+    let mut player = SpotifyPlayer::new(connection, credentials, cache_dir.clone(), options);
+
+    player.emit(Event::UserConnected { username });
+
+    if let Some(credentials) = Cache::new(PathBuf::from(&cache_dir), true).credentials() {
+        player.emit(Event::Credentials { credentials });
+    }
+
+    player.enable_connect(connect_config);
+    player.follow_discovery(discovery, credentials_rx, cache_dir);
+
+    Ok(player)
+}
+
+// Calls back with `(error)` carrying the session error's `code`, or `(null, spotify)`
+fn call_back_connected(cx: &mut TaskContext, this: Handle<JsValue>, callback: Handle<JsFunction>, result: Result<SpotifyPlayer, SessionError>) -> NeonResult<()> {
+    match result {
+        Ok(player) => {
+            let instance = spotify_instance(cx, player)?;
+            let null = cx.null();
+
+            callback.call(cx, this, vec![null.upcast(), instance])?;
+        },
+        Err(e) => {
+            let err = error_to_js(cx, &e)?;
+
+            callback.call(cx, this, vec![err])?;
+        }
+    }
+
+    Ok(())
+}
+
+// Wraps a connected player in a new `JsSpotify`
+fn spotify_instance<'a>(cx: &mut TaskContext<'a>, player: SpotifyPlayer) -> JsResult<'a, JsValue> {
+    let ctor = JsSpotify::constructor(cx)?;
+    let no_args: Vec<Handle<JsValue>> = vec![];
+    let mut instance = ctor.construct(cx, no_args)?;
+
+    // With ring delivery the PCM is written straight into memory owned by this `ArrayBuffer`.
+    // The ring holds a persistent handle to it, so it can't be collected while a player may still write.
+    if let Some(ring) = player.audio_ring() {
+        let mut buffer = JsArrayBuffer::new(cx, ring.byte_len() as u32)?;
+
+        let storage = cx.borrow_mut(&mut buffer, |data| data.as_mut_slice::<u8>().as_mut_ptr());
+
+        // An `EventHandler` is the only persistent handle there is, it is never called
+        let keep_alive = JsFunction::new(cx, ignore)?;
+        let root = EventHandler::new(&*cx, buffer, keep_alive);
+
+        unsafe { ring.attach(storage, Box::new(root)) };
+
+        instance.set(cx, "audioRing", buffer)?;
+    }
+
+    let sample_rate = cx.number(player.sample_rate());
+    let format = cx.empty_object();
+
+    format_to_js(cx, player.format(), player.sample_rate(), format)?;
+
+    instance.set(cx, "sampleRate", sample_rate)?;
+    instance.set(cx, "format", format)?;
+
+    {
+        let guard = cx.lock();
+        let mut spotify = instance.borrow_mut(&guard);
+
+        spotify.player = Some(player);
+    }
+
+    Ok(instance.upcast())
+}
+
+fn ignore(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    Ok(cx.undefined())
+}
+
+// Picks the login method from the options: an OAuth access token, a stored credentials blob,
+// a username/password pair or, when none is given, the credentials file left in the cache by a previous login
+fn credentials_from_options<'a>(cx: &mut FunctionContext<'a>, options: Handle<'a, JsObject>, cache_dir: &str) -> NeonResult<Credentials> {
+    if let Ok(stored) = options.get(cx, "credentials")?.downcast::<JsObject>() {
+        if let Ok(token) = stored.get(cx, "accessToken")?.downcast::<JsString>() {
+            let username = match stored.get(cx, "username")?.downcast::<JsString>() {
+                Ok(username) => username.value(),
+                Err(_) => String::new()
+            };
+
+            return Ok(Credentials {
+                username,
+                auth_type: AuthenticationType::AUTHENTICATION_SPOTIFY_TOKEN,
+                auth_data: token.value().into_bytes()
+            });
+        }
+
+        let username = stored.get(cx, "username")?.downcast_or_throw::<JsString, _>(cx)?.value();
+        let auth_type = stored.get(cx, "authType")?.downcast_or_throw::<JsString, _>(cx)?.value();
+        let auth_data = stored.get(cx, "authData")?.downcast_or_throw::<JsString, _>(cx)?.value();
+
+        let auth_type = match session::parse_auth_type(&auth_type) {
+            Some(auth_type) => auth_type,
+            None => return cx.throw_error(format!("Invalid auth type: {}", auth_type))
+        };
+
+        let auth_data = match base64::decode(&auth_data) {
+            Ok(auth_data) => auth_data,
+            Err(_) => return cx.throw_error("Invalid auth data, expected base64")
+        };
+
+        return Ok(Credentials {
+            username,
+            auth_type,
+            auth_data
+        });
+    }
+
+    if let Ok(password) = options.get(cx, "password")?.downcast::<JsString>() {
+        let username = options.get(cx, "username")?.downcast_or_throw::<JsString, _>(cx)?.value();
+
+        return Ok(Credentials::with_password(username, password.value()));
+    }
+
+    match Cache::new(PathBuf::from(cache_dir), true).credentials() {
+        Some(credentials) => Ok(credentials),
+        None => cx.throw_error("No credentials given and none found in the cache")
+    }
+}
+
+// Options shared by `connect` and `discover`, returns the player options and the cache directory
+fn player_options_from_options<'a>(cx: &mut FunctionContext<'a>, options: Handle<'a, JsObject>) -> NeonResult<(PlayerOptions, String)> {
+    let bitrate = options.get(cx, "quality")?.downcast_or_throw::<JsString, _>(cx)?.value();
+    let cache_dir = options.get(cx, "cacheDir")?.downcast_or_throw::<JsString, _>(cx)?.value();
+
+    let quality = match Bitrate::from_str(&bitrate) {
+        Ok(quality) => quality,
+        Err(_) => return cx.throw_error(format!("Invalid quality: {}", bitrate))
+    };
+
+    let initial_volume = match options.get(cx, "initialVolume")?.downcast::<JsNumber>() {
+        Ok(volume) => volume.value() as u16,
+        Err(_) => std::u16::MAX
+    };
+
+    let volume_ctrl = match options.get(cx, "volumeCtrl")?.downcast::<JsString>() {
+        Ok(volume_ctrl) => match VolumeCtrl::from_str(&volume_ctrl.value()) {
+            Ok(volume_ctrl) => volume_ctrl,
+            Err(_) => return cx.throw_error(format!("Invalid volume control: {}", volume_ctrl.value()))
+        },
+        Err(_) => VolumeCtrl::Linear
+    };
+
+    let sample_rate = match options.get(cx, "outputSampleRate")?.downcast::<JsNumber>() {
+        Ok(rate) if SUPPORTED_SAMPLE_RATES.iter().any(|supported| *supported as f64 == rate.value()) => rate.value() as u64,
+        Ok(rate) => return cx.throw_range_error(format!("Unsupported output sample rate: {}, use one of {:?}", rate.value(), SUPPORTED_SAMPLE_RATES)),
+        Err(_) => pacing::DECODER_SAMPLE_RATE
+    };
+
+    let mut format = OutputFormat::default();
+
+    if let Ok(sample_format) = options.get(cx, "outputSampleFormat")?.downcast::<JsString>() {
+        format.sample_format = match SampleFormat::from_str(&sample_format.value()) {
+            Ok(sample_format) => sample_format,
+            Err(_) => return cx.throw_error(format!("Invalid sample format: {}", sample_format.value()))
+        };
+    }
+
+    if let Ok(layout) = options.get(cx, "outputChannelLayout")?.downcast::<JsString>() {
+        format.layout = match ChannelLayout::from_str(&layout.value()) {
+            Ok(layout) => layout,
+            Err(_) => return cx.throw_error(format!("Invalid channel layout: {}", layout.value()))
+        };
+    }
+
+    if let Ok(channels) = options.get(cx, "outputChannels")?.downcast::<JsString>() {
+        format.channels = match Channels::from_str(&channels.value()) {
+            Ok(channels) => channels,
+            Err(_) => return cx.throw_error(format!("Invalid channels: {}", channels.value()))
+        };
+    }
+
+    let pacing = match options.get(cx, "pacing")?.downcast::<JsString>() {
+        Ok(pacing) => match Pacing::from_str(&pacing.value()) {
+            Ok(pacing) => pacing,
+            Err(_) => return cx.throw_error(format!("Invalid pacing: {}", pacing.value()))
+        },
+        Err(_) => Pacing::Realtime
+    };
+
+    // Only used by consumer pacing. Sizes are in output frames, the defaults come at the decoder rate.
+    let pacing = match (pacing, options.get(cx, "audioBufferMs")?.downcast::<JsNumber>()) {
+        (Pacing::Consumer { .. }, Ok(buffer_ms)) => Pacing::Consumer { buffer: pacing::frames_for(buffer_ms.value() as u32, sample_rate) },
+        (Pacing::Consumer { buffer }, Err(_)) => Pacing::Consumer { buffer: buffer * sample_rate / pacing::DECODER_SAMPLE_RATE },
+        (pacing, _) => pacing
+    };
+
+    let audio_delivery = match options.get(cx, "audioDelivery")?.downcast::<JsString>() {
+        Ok(audio_delivery) => match AudioDelivery::from_str(&audio_delivery.value()) {
+            Ok(audio_delivery) => audio_delivery,
+            Err(_) => return cx.throw_error(format!("Invalid audio delivery: {}", audio_delivery.value()))
+        },
+        Err(_) => AudioDelivery::Copy
+    };
+
+    // Only used by ring delivery
+    let audio_delivery = match (audio_delivery, options.get(cx, "audioRingMs")?.downcast::<JsNumber>()) {
+        (AudioDelivery::Ring { .. }, Ok(ring_ms)) => AudioDelivery::Ring { frames: pacing::frames_for(ring_ms.value() as u32, sample_rate) },
+        (AudioDelivery::Ring { frames }, Err(_)) => AudioDelivery::Ring { frames: frames * sample_rate / pacing::DECODER_SAMPLE_RATE },
+        (audio_delivery, _) => audio_delivery
+    };
+
+    let position_interval = match options.get(cx, "positionIntervalMs")?.downcast::<JsNumber>() {
+        Ok(interval_ms) if interval_ms.value() > 0.0 => Some(pacing::frames_for(interval_ms.value() as u32, sample_rate)),
+        _ => None
+    };
+
+    let command_timeout = match options.get(cx, "commandTimeoutMs")?.downcast::<JsNumber>() {
+        Ok(timeout_ms) if timeout_ms.value() > 0.0 => Duration::from_millis(timeout_ms.value() as u64),
+        _ => DEFAULT_COMMAND_TIMEOUT
+    };
+
+    let config = player_config_update_from_options(cx, options)?;
+
+    Ok((PlayerOptions {
+        quality,
+        initial_volume,
+        volume_ctrl,
+        pacing,
+        audio_delivery,
+        sample_rate: sample_rate as u32,
+        format,
+        position_interval,
+        command_timeout,
+        normalisation: config.normalisation.unwrap_or(false),
+        normalisation_pregain: config.normalisation_pregain.unwrap_or(0.0),
+        gapless: config.gapless.unwrap_or(true)
+    }, cache_dir))
+}
+
+// Shared by the connect options and `setPlayerConfig`, options that aren't set stay `None`
+fn player_config_update_from_options<'a, C: Context<'a>>(cx: &mut C, options: Handle<'a, JsObject>) -> NeonResult<PlayerConfigUpdate> {
+    let mut update = PlayerConfigUpdate::default();
+
+    if let Ok(normalisation) = options.get(cx, "normalisation")?.downcast::<JsBoolean>() {
+        update.normalisation = Some(normalisation.value());
+    }
+
+    if let Ok(pregain) = options.get(cx, "normalisationPregainDb")?.downcast::<JsNumber>() {
+        if !pregain.value().is_finite() {
+            return cx.throw_range_error("Normalisation pregain must be a finite number");
+        }
+
+        update.normalisation_pregain = Some(pregain.value() as f32);
+    }
+
+    if let Ok(gapless) = options.get(cx, "gapless")?.downcast::<JsBoolean>() {
+        update.gapless = Some(gapless.value());
+    }
+
+    Ok(update)
+}
+
+fn connect_config_from_options<'a, C: Context<'a>>(cx: &mut C, options: Handle<'a, JsObject>) -> NeonResult<ConnectConfig> {
+    let device_name = options.get(cx, "deviceName")?.downcast_or_throw::<JsString, _>(cx)?.value();
+    let device_type = options.get(cx, "deviceType")?.downcast_or_throw::<JsString, _>(cx)?.value();
+    let initial_volume = options.get(cx, "initialVolume")?.downcast_or_throw::<JsNumber, _>(cx)?.value();
+    let volume_ctrl = options.get(cx, "volumeCtrl")?.downcast_or_throw::<JsString, _>(cx)?.value();
+
+    let device_type = match DeviceType::from_str(&device_type) {
+        Ok(device_type) => device_type,
+        Err(_) => return cx.throw_error(format!("Invalid device type: {}", device_type))
+    };
+
+    let volume_ctrl = match VolumeCtrl::from_str(&volume_ctrl) {
+        Ok(volume_ctrl) => volume_ctrl,
+        Err(_) => return cx.throw_error(format!("Invalid volume control: {}", volume_ctrl))
+    };
+
+    Ok(ConnectConfig {
+        name: device_name,
+        device_type,
+        volume: initial_volume as u16,
+        autoplay: true,
+        volume_ctrl
+    })
+}
+
+fn connect(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let options = cx.argument::<JsObject>(0)?;
+    let cb = cx.argument::<JsFunction>(1)?;
+
+    let (player_options, cache_dir) = player_options_from_options(&mut cx, options)?;
+
+    let credentials = credentials_from_options(&mut cx, options, &cache_dir)?;
+
+    let task = ConnectTask {
+        credentials,
+        options: player_options,
+        cache_dir
+    };
+
+    task.schedule(cb);
+
+    Ok(cx.undefined())
+}
+
+fn discover(mut cx: FunctionContext) -> JsResult<JsDiscovery> {
+    let options = cx.argument::<JsObject>(0)?;
+    let cb = cx.argument::<JsFunction>(1)?;
+
+    let (player_options, cache_dir) = player_options_from_options(&mut cx, options)?;
+    let connect_config = connect_config_from_options(&mut cx, options)?;
+
+    // 0 lets the OS pick a free port for the `addUser` endpoint
+    let port = match options.get(&mut cx, "discoveryPort")?.downcast::<JsNumber>() {
+        Ok(port) => port.value() as u16,
+        Err(_) => 0
+    };
+
+    let undefined = cx.undefined();
+    let handler = EventHandler::new(&cx, undefined, cb);
+
+    let ctor = JsDiscovery::constructor(&mut cx)?;
+    let no_args: Vec<Handle<JsValue>> = vec![];
+    let mut pending = ctor.construct(&mut cx, no_args)?;
+
+    let session_config = SessionConfig::default();
+
+    let (discovery, credentials_rx) = match Discovery::start(connect_config.clone(), session_config.device_id.clone(), port) {
+        Ok(started) => started,
+        Err(e) => {
+            let result = Err(SessionError::Other(format!("Could not start discovery: {}", e)));
+
+            handler.schedule_with(move |cx, this, cb| {
+                let _ = call_back_connected(cx, this, cb, result);
+            });
+
+            return Ok(pending);
+        }
+    };
+
+    let discovery = Arc::new(Mutex::new(Some(discovery)));
+
+    {
+        let guard = cx.lock();
+        let mut state = pending.borrow_mut(&guard);
+
+        state.discovery = discovery.clone();
+    }
+
+    thread::spawn(move || {
+        let result = connect_discovered(discovery, credentials_rx, session_config, connect_config, player_options, cache_dir);
+
+        handler.schedule_with(move |cx, this, cb| {
+            let _ = call_back_connected(cx, this, cb, result);
+        });
+    });
+
+    Ok(pending)
+}
+
+fn track_ids_argument(cx: &mut MethodContext<JsSpotify>, index: i32) -> NeonResult<Vec<SpotifyId>> {
+    let tracks = cx.argument::<JsArray>(index)?.to_vec(cx)?;
+
+    let mut track_ids = Vec::with_capacity(tracks.len());
+
+    for track in tracks {
+        let track = track.downcast_or_throw::<JsString, _>(cx)?.value();
+
+        match parse_media_id(&track) {
+            Ok(track_id) => track_ids.push(track_id),
+            Err(err) => return cx.throw_error(err)
+        }
+    }
+
+    Ok(track_ids)
+}
+
+// A track or episode given as a base62 id, a Spotify URI or a share link
+fn media_id_argument(cx: &mut MethodContext<JsSpotify>, index: i32) -> NeonResult<SpotifyId> {
+    let media = cx.argument::<JsString>(index)?.value();
+
+    parse_media_id(&media).or_else(|err| cx.throw_error(err))
+}
+
+fn queue_index_argument(cx: &mut MethodContext<JsSpotify>, index: i32) -> NeonResult<usize> {
+    let value = cx.argument::<JsNumber>(index)?.value();
+
+    if value < 0.0 || value.fract() != 0.0 {
+        return cx.throw_range_error("Queue index must be a non-negative integer");
+    }
+
+    Ok(value as usize)
+}
+
+// Settles the optional callback at `index` once the player confirms the command, otherwise nobody waits.
+// The outcome is pushed to the event loop, nothing blocks on it in the meantime.
+fn settle_command(cx: &mut MethodContext<JsSpotify>, waiter: CommandWaiter, index: i32) {
+    if let Some(cb) = cx.argument_opt(index) {
+        if let Ok(cb) = cb.downcast::<JsFunction>() {
+            let undefined = cx.undefined();
+            let handler = EventHandler::new(&*cx, undefined, cb);
+
+            waiter.settle_with(move |result| {
+                handler.schedule_with(move |cx, this, cb| {
+                    let _ = commands::call_back(cx, this, cb, result);
+                });
+            });
+        }
+    }
+}
+
+// Control events and audio data have separate channels, `audio` picks which one to work on.
+// Each channel is either pushed to a callback with `listen` or polled, never both.
+fn listen_events(mut cx: MethodContext<JsSpotify>, audio: bool) -> JsResult<JsValue> {
+    let cb = cx.argument::<JsFunction>(0)?;
+    let mut this = cx.this();
+
+    // Called without a receiver, rooting `this` would keep the instance from ever being collected.
+    // The callback and its uv handle still stay alive, along with the event loop, until `shutdown` closes the channel.
+    let undefined = cx.undefined();
+    let handler = EventHandler::new(&cx, undefined, cb);
+
+    let result = {
+        let guard = cx.lock();
+        let mut spotify = this.borrow_mut(&guard);
+
+        if spotify.is_listening(audio) {
+            Err("Already listening for these events")
+        } else {
+            let listening = spotify.player().map(|player| {
+                if audio {
+                    player.emitter.listen_audio(handler)
+                } else {
+                    player.emitter.listen(handler)
+                }
+            });
+
+            if listening.is_ok() {
+                spotify.set_listening(audio);
+            }
+
+            listening
+        }
+    };
+
+    result.or_else(|err| cx.throw_error(err))?;
+
+    Ok(cx.undefined().upcast())
+}
+
+fn poll_events(mut cx: MethodContext<JsSpotify>, audio: bool) -> JsResult<JsValue> {
+    let cb = cx.argument::<JsFunction>(0).expect("callback function");
+    let this = cx.this();
+
+    let max_events = match cx.argument_opt(1) {
+        Some(max_events) => max_events.downcast_or_throw::<JsNumber, _>(&mut cx)?.value() as usize,
+        None => DEFAULT_POLL_BATCH
+    };
+
+    let events = cx.borrow(&this, |spotify| {
+        if spotify.is_listening(audio) {
+            return Err("These events are pushed to a listen callback, polling is unavailable");
+        }
+
+        spotify.player.as_ref().map(|player| {
+            if audio {
+                Arc::clone(&player.emitter.audio)
+            } else {
+                Arc::clone(&player.emitter.events)
+            }
+        }).ok_or(NOT_CONNECTED)
+    });
+
+    let events = match events {
+        Ok(events) => events,
+        Err(err) => return cx.throw_error(err)
+    };
+
+    let emitter = EventEmitterTask(events, cmp::max(max_events, 1));
+
+    emitter.schedule(cb);
+
+    Ok(JsUndefined::new().upcast())
 }
 
 declare_types! {
-    pub class JsSpotify for Spotify {
-        init(mut cx) {
-            // env::set_var("RUST_LOG", "debug");
+    pub class JsDiscovery for PendingDiscovery {
+        init(_cx) {
+            // Instances are created by `discover()`
+            Ok(PendingDiscovery {
+                discovery: Arc::new(Mutex::new(None))
+            })
+        }
 
-            let options = cx.argument::<JsObject>(0)?;
-            
-            let username = options.get(&mut cx, "username")?.downcast::<JsString>().unwrap();
-            let password = options.get(&mut cx, "password")?.downcast::<JsString>().unwrap();
-            let bitrate = options.get(&mut cx, "quality")?.downcast::<JsString>().unwrap();
-            let cache_dir = options.get(&mut cx, "cacheDir")?.downcast::<JsString>().unwrap();
+        // Stops announcing the device, unless someone connected already. The callback then gets a STOPPED error.
+        method stop(mut cx) {
+            let mut this = cx.this();
+
+            let discovery = {
+                let guard = cx.lock();
+                let pending = this.borrow_mut(&guard);
 
-            let player = SpotifyPlayer::new(username.value(), password.value(), Bitrate::from_str(&bitrate.value()).unwrap(), cache_dir.value());
+                pending.discovery.clone()
+            };
+
+            // Dropping it joins the discovery thread, which disconnects the credentials the login waits on
+            let stopped = discovery.lock().unwrap().take();
+
+            drop(stopped);
+
+            Ok(cx.undefined().upcast())
+        }
+    }
 
+    pub class JsSpotify for Spotify {
+        init(_cx) {
+            // Instances are created by `connect()` or `discover()` once the session is up
             Ok(Spotify {
-                player: player
+                player: None,
+                listening: false,
+                listening_audio: false
             })
         }
 
@@ -55,79 +688,501 @@ declare_types! {
 
             let options = cx.argument::<JsObject>(0)?;
 
-            let device_name = options.get(&mut cx, "deviceName")?.downcast::<JsString>().unwrap().value();
-            let device_type = options.get(&mut cx, "deviceType")?.downcast::<JsString>().unwrap().value();
-            let initial_volume = options.get(&mut cx, "initialVolume")?.downcast::<JsNumber>().unwrap().value();
-            let volume_ctrl = options.get(&mut cx, "volumeCtrl")?.downcast::<JsString>().unwrap().value();
+            let config = connect_config_from_options(&mut cx, options)?;
 
-            {
+            let result = {
                 let guard = cx.lock();
                 let mut spotify = this.borrow_mut(&guard);
 
-                println!("enabling connect");
+                spotify.player().map(|player| player.enable_connect(config))
+            };
 
-                spotify.player.enable_connect(device_name, DeviceType::from_str(&device_type).unwrap(), initial_volume as u16, VolumeCtrl::from_str(&volume_ctrl).unwrap());
-            }
+            result.or_else(|err| cx.throw_error(err))?;
+
+            Ok(cx.undefined().upcast())
+        }
+
+        method disableConnect(mut cx) {
+            let mut this = cx.this();
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().map(|player| player.disable_connect())
+            };
+
+            result.or_else(|err| cx.throw_error(err))?;
+
+            Ok(cx.undefined().upcast())
+        }
+
+        method connectCommand(mut cx) {
+            let mut this = cx.this();
+            let command = cx.argument::<JsString>(0)?.value();
+
+            let command = match ConnectCommand::from_str(&command) {
+                Ok(command) => command,
+                Err(_) => return cx.throw_error(format!("Unknown Connect command: {}", command))
+            };
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().and_then(|player| player.connect_command(command))
+            };
+
+            result.or_else(|err| cx.throw_error(err))?;
 
             Ok(cx.undefined().upcast())
         }
 
         method play(mut cx) {
             let mut this = cx.this();
-            let track_id: Handle<JsString> = cx.argument::<JsString>(0)?;
+            let track_id = media_id_argument(&mut cx, 0)?;
+
+            let mut load_options = LoadOptions::default();
+
+            if let Some(options) = cx.argument_opt(1) {
+                if let Ok(options) = options.downcast::<JsObject>() {
+                    if let Ok(position_ms) = options.get(&mut cx, "positionMs")?.downcast::<JsNumber>() {
+                        if position_ms.value() < 0.0 {
+                            return cx.throw_range_error("Position must not be negative");
+                        }
+
+                        load_options.position_ms = position_ms.value() as u32;
+                    }
+
+                    if let Ok(paused) = options.get(&mut cx, "paused")?.downcast::<JsBoolean>() {
+                        load_options.start_playing = !paused.value();
+                    }
+                }
+            }
 
-            {
+            let result = {
                 let guard = cx.lock();
                 let mut spotify = this.borrow_mut(&guard);
 
-                spotify.player.play(track_id.value());
+                spotify.player().map(|player| player.play(track_id, load_options))
+            };
+
+            let (play_request_id, waiter) = result.or_else(|err| cx.throw_error(err))?;
+
+            settle_command(&mut cx, waiter, 2);
+
+            Ok(cx.number(play_request_id as f64).upcast())
+        }
+
+        method setVolume(mut cx) {
+            let mut this = cx.this();
+            let volume = cx.argument::<JsNumber>(0)?.value();
+
+            if volume < 0.0 || volume > std::u16::MAX as f64 {
+                return cx.throw_range_error("Volume must be between 0 and 65535");
             }
 
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().map(|player| player.set_volume(volume as u16))
+            };
+
+            result.or_else(|err| cx.throw_error(err))?;
+
             Ok(cx.undefined().upcast())
         }
 
+        method setPlayerConfig(mut cx) {
+            let mut this = cx.this();
+            let options = cx.argument::<JsObject>(0)?;
+
+            let update = player_config_update_from_options(&mut cx, options)?;
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().map(|player| player.set_player_config(update))
+            };
+
+            result.or_else(|err| cx.throw_error(err))?;
+
+            Ok(cx.undefined().upcast())
+        }
+
+        method getVolume(mut cx) {
+            let mut this = cx.this();
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().map(|player| player.get_volume())
+            };
+
+            let volume = result.or_else(|err| cx.throw_error(err))?;
+
+            Ok(cx.number(volume).upcast())
+        }
+
         method stop(mut cx) {
-            let this = cx.this();
+            let mut this = cx.this();
 
-            {
+            let result = {
                 let guard = cx.lock();
-                let spotify = this.borrow(&guard);
+                let mut spotify = this.borrow_mut(&guard);
 
-                spotify.player.stop();
-            }
+                spotify.player().and_then(|player| player.stop())
+            };
+
+            let waiter = result.or_else(|err| cx.throw_error(err))?;
+
+            settle_command(&mut cx, waiter, 0);
 
             Ok(cx.undefined().upcast())
         }
 
         method pause(mut cx) {
-            let this = cx.this();
+            let mut this = cx.this();
 
-            {
+            let result = {
                 let guard = cx.lock();
-                let spotify = this.borrow(&guard);
+                let mut spotify = this.borrow_mut(&guard);
 
-                spotify.player.pause();
-            }
+                spotify.player().and_then(|player| player.pause())
+            };
+
+            let waiter = result.or_else(|err| cx.throw_error(err))?;
+
+            settle_command(&mut cx, waiter, 0);
 
             Ok(cx.undefined().upcast())
         }
 
         method seek(mut cx) {
-            let this = cx.this();
+            let mut this = cx.this();
             let position_ms: Handle<JsNumber> = cx.argument::<JsNumber>(0)?;
 
-            {
+            let result = {
                 let guard = cx.lock();
-                let spotify = this.borrow(&guard);
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().and_then(|player| player.seek(position_ms.value() as u32))
+            };
+
+            let waiter = result.or_else(|err| cx.throw_error(err))?;
+
+            settle_command(&mut cx, waiter, 1);
+
+            Ok(cx.undefined().upcast())
+        }
+
+        method resume(mut cx) {
+            let mut this = cx.this();
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().and_then(|player| player.resume())
+            };
+
+            let waiter = result.or_else(|err| cx.throw_error(err))?;
+
+            settle_command(&mut cx, waiter, 0);
+
+            Ok(cx.undefined().upcast())
+        }
+
+        method toggle(mut cx) {
+            let mut this = cx.this();
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().and_then(|player| player.toggle())
+            };
+
+            let waiter = result.or_else(|err| cx.throw_error(err))?;
+
+            settle_command(&mut cx, waiter, 0);
+
+            Ok(cx.undefined().upcast())
+        }
+
+        method seekBy(mut cx) {
+            let mut this = cx.this();
+
+            let delta_ms = cx.argument::<JsNumber>(0)?.value();
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().and_then(|player| player.seek_by(delta_ms as i64))
+            };
+
+            let waiter = result.or_else(|err| cx.throw_error(err))?;
+
+            settle_command(&mut cx, waiter, 1);
+
+            Ok(cx.undefined().upcast())
+        }
+
+        method seekFraction(mut cx) {
+            let mut this = cx.this();
+
+            let fraction = cx.argument::<JsNumber>(0)?.value();
 
-                spotify.player.seek(position_ms.value() as u32);
+            if fraction < 0.0 || fraction > 1.0 {
+                return cx.throw_range_error("Fraction must be between 0 and 1");
             }
 
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().and_then(|player| player.seek_fraction(fraction))
+            };
+
+            let waiter = result.or_else(|err| cx.throw_error(err))?;
+
+            settle_command(&mut cx, waiter, 1);
+
+            Ok(cx.undefined().upcast())
+        }
+
+        method restart(mut cx) {
+            let mut this = cx.this();
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().and_then(|player| player.restart())
+            };
+
+            let waiter = result.or_else(|err| cx.throw_error(err))?;
+
+            settle_command(&mut cx, waiter, 0);
+
+            Ok(cx.undefined().upcast())
+        }
+
+        method preload(mut cx) {
+            let mut this = cx.this();
+
+            let track_id = media_id_argument(&mut cx, 0)?;
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().map(|player| player.preload(track_id))
+            };
+
+            result.or_else(|err| cx.throw_error(err))?;
+
+            Ok(cx.undefined().upcast())
+        }
+
+        method enqueue(mut cx) {
+            let mut this = cx.this();
+
+            let tracks = track_ids_argument(&mut cx, 0)?;
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().map(|player| player.enqueue(tracks))
+            };
+
+            result.or_else(|err| cx.throw_error(err))?;
+
+            Ok(cx.undefined().upcast())
+        }
+
+        method insertNext(mut cx) {
+            let mut this = cx.this();
+
+            let tracks = track_ids_argument(&mut cx, 0)?;
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().map(|player| player.insert_next(tracks))
+            };
+
+            result.or_else(|err| cx.throw_error(err))?;
+
+            Ok(cx.undefined().upcast())
+        }
+
+        method removeFromQueue(mut cx) {
+            let mut this = cx.this();
+
+            let index = queue_index_argument(&mut cx, 0)?;
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().and_then(|player| player.remove_from_queue(index))
+            };
+
+            result.or_else(|err| cx.throw_error(err))?;
+
+            Ok(cx.undefined().upcast())
+        }
+
+        method moveInQueue(mut cx) {
+            let mut this = cx.this();
+
+            let from = queue_index_argument(&mut cx, 0)?;
+            let to = queue_index_argument(&mut cx, 1)?;
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().and_then(|player| player.move_in_queue(from, to))
+            };
+
+            result.or_else(|err| cx.throw_error(err))?;
+
+            Ok(cx.undefined().upcast())
+        }
+
+        method clearQueue(mut cx) {
+            let mut this = cx.this();
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().map(|player| player.clear_queue())
+            };
+
+            result.or_else(|err| cx.throw_error(err))?;
+
+            Ok(cx.undefined().upcast())
+        }
+
+        method setShuffle(mut cx) {
+            let mut this = cx.this();
+
+            let shuffle = cx.argument::<JsBoolean>(0)?.value();
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().map(|player| player.set_shuffle(shuffle))
+            };
+
+            result.or_else(|err| cx.throw_error(err))?;
+
+            Ok(cx.undefined().upcast())
+        }
+
+        method setRepeat(mut cx) {
+            let mut this = cx.this();
+
+            let repeat = cx.argument::<JsString>(0)?.value();
+
+            let repeat = match RepeatMode::from_str(&repeat) {
+                Ok(repeat) => repeat,
+                Err(_) => return cx.throw_error(format!("Invalid repeat mode: {}", repeat))
+            };
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().map(|player| player.set_repeat(repeat))
+            };
+
+            result.or_else(|err| cx.throw_error(err))?;
+
+            Ok(cx.undefined().upcast())
+        }
+
+        method getQueue(mut cx) {
+            let mut this = cx.this();
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().map(|player| player.get_queue())
+            };
+
+            let queue = result.or_else(|err| cx.throw_error(err))?;
+
+            let o = cx.empty_object();
+
+            queue_to_js(&mut cx, queue, o)?;
+
+            Ok(o.upcast())
+        }
+
+        method next(mut cx) {
+            let mut this = cx.this();
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().and_then(|player| player.next())
+            };
+
+            let waiter = result.or_else(|err| cx.throw_error(err))?;
+
+            settle_command(&mut cx, waiter, 0);
+
+            Ok(cx.undefined().upcast())
+        }
+
+        method previous(mut cx) {
+            let mut this = cx.this();
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().and_then(|player| player.previous())
+            };
+
+            let waiter = result.or_else(|err| cx.throw_error(err))?;
+
+            settle_command(&mut cx, waiter, 0);
+
+            Ok(cx.undefined().upcast())
+        }
+
+        method shutdown(mut cx) {
+            let mut this = cx.this();
+
+            let player = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player.take()
+            };
+
+            // Dropping the player tears down Connect, playback, the session and its thread
+            drop(player);
+
             Ok(cx.undefined().upcast())
         }
 
         method getToken(mut cx) {
-            let this = cx.this();
+            let mut this = cx.this();
             let ctor = JsAccessToken::constructor(&mut cx)?;
 
             let client_id: Handle<JsString> = cx.argument::<JsString>(0)?;
@@ -136,25 +1191,29 @@ declare_types! {
 
             let mut token: Option<AccessToken> = None;
 
-            {
-                let guard = cx.lock();
-                let spotify = this.borrow(&guard);
-
-                spotify.player.get_token(client_id.value(), scopes.value(), |tok| {
-                    match tok {
-                        Some(t) => {
-                            token = Some(AccessToken {
-                                token: t.access_token,
-                                scope: t.scope,
-                                expires_in: t.expires_in
-                            });
-                        },
-                        None => {
-                            token = None;
-                        }
-                    };
-                });
-            }
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().map(|player| {
+                    player.get_token(client_id.value(), scopes.value(), |tok| {
+                        match tok {
+                            Some(t) => {
+                                token = Some(AccessToken {
+                                    token: t.access_token,
+                                    scope: t.scope,
+                                    expires_in: t.expires_in
+                                });
+                            },
+                            None => {
+                                token = None;
+                            }
+                        };
+                    })
+                })
+            };
+
+            result.or_else(|err| cx.throw_error(err))?;
 
             match token {
                 Some(t) => {
@@ -186,16 +1245,71 @@ declare_types! {
             Ok(cx.undefined().upcast())
         }
 
-        method poll(mut cx) {
-            let cb = cx.argument::<JsFunction>(0).expect("callback function");
-            let this = cx.this();
+        method getState(mut cx) {
+            let mut this = cx.this();
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().map(|player| player.get_state())
+            };
+
+            let snapshot = result.or_else(|err| cx.throw_error(err))?;
+
+            let o = cx.empty_object();
+
+            let track_id: Handle<JsValue> = match snapshot.track_id {
+                Some(track_id) => cx.string(track_id.to_base62()).upcast(),
+                None => cx.null().upcast()
+            };
+            let status = cx.string(snapshot.status.name());
+            let position = cx.number(snapshot.position_ms);
+            let duration = cx.number(snapshot.duration_ms);
+
+            o.set(&mut cx, "trackId", track_id)?;
+            o.set(&mut cx, "status", status)?;
+            o.set(&mut cx, "positionMs", position)?;
+            o.set(&mut cx, "durationMs", duration)?;
+
+            Ok(o.upcast())
+        }
+
+        method acknowledgeAudio(mut cx) {
+            let mut this = cx.this();
 
-            let events = cx.borrow(&this, |spotify| Arc::clone(&spotify.player.emitter.events));
-            let emitter = EventEmitterTask(events);
+            let frames = cx.argument::<JsNumber>(0)?.value();
 
-            emitter.schedule(cb);
+            if frames < 0.0 {
+                return cx.throw_range_error("Frames must not be negative");
+            }
+
+            let result = {
+                let guard = cx.lock();
+                let mut spotify = this.borrow_mut(&guard);
+
+                spotify.player().map(|player| player.acknowledge_audio(frames as u64))
+            };
+
+            result.or_else(|err| cx.throw_error(err))?;
+
+            Ok(cx.undefined().upcast())
+        }
+
+        method listen(cx) {
+            listen_events(cx, false)
+        }
+
+        method listenAudio(cx) {
+            listen_events(cx, true)
+        }
+
+        method poll(cx) {
+            poll_events(cx, false)
+        }
 
-            Ok(JsUndefined::new().upcast())
+        method pollAudio(cx) {
+            poll_events(cx, true)
         }
     }
 }
@@ -205,6 +1319,9 @@ register_module!(mut cx, {
     env::set_var("RUST_BACKTRACE", "full");
 
     cx.export_class::<JsSpotify>("Spotify")?;
+    cx.export_class::<JsDiscovery>("Discovery")?;
+    cx.export_function("connect", connect)?;
+    cx.export_function("discover", discover)?;
 
     Ok(())
 });
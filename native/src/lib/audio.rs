@@ -0,0 +1,224 @@
+use std::cmp;
+use std::ptr;
+use std::slice;
+use std::str::FromStr;
+use std::sync::{ Arc, Condvar, Mutex };
+use std::sync::atomic::{ AtomicBool, AtomicPtr, AtomicUsize, Ordering };
+
+use super::pacing::DECODER_SAMPLE_RATE;
+
+// Frames come out of the decoder as interleaved stereo
+const INPUT_CHANNELS: usize = 2;
+// Chunks kept around for reuse in recycled mode, more than enough to cover the event channel
+const MAX_RECYCLED_CHUNKS: usize = 64;
+
+// How the sink hands PCM over to JS
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioDelivery {
+    // A freshly allocated chunk per write
+    Copy,
+    // Chunks reused once they've been copied into a `Buffer`, JS still gets a new `Buffer` for each
+    Recycled,
+    // Written in place into a ring buffer JS reads from, `frames` long
+    Ring { frames: u64 }
+}
+
+impl FromStr for AudioDelivery {
+    type Err = ();
+
+    // The ring holds two seconds at the decoder rate unless `PlayerOptions` say otherwise
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "copy" => Ok(AudioDelivery::Copy),
+            "recycled" => Ok(AudioDelivery::Recycled),
+            "ring" => Ok(AudioDelivery::Ring { frames: 2 * DECODER_SAMPLE_RATE }),
+            _ => Err(())
+        }
+    }
+}
+
+// Native chunks for recycled delivery, the `Buffer`s handed to JS are never reused
+#[derive(Clone)]
+pub struct ChunkPool {
+    chunks: Arc<Mutex<Vec<Vec<u8>>>>
+}
+
+impl ChunkPool {
+    pub fn new() -> ChunkPool {
+        ChunkPool {
+            chunks: Arc::new(Mutex::new(Vec::new()))
+        }
+    }
+
+    // A recycled chunk of `len` bytes, only allocating while the pool is still warming up
+    pub fn take(&self, len: usize) -> Vec<u8> {
+        let mut chunk = self.chunks.lock().unwrap().pop().unwrap_or_default();
+
+        chunk.resize(len, 0);
+
+        chunk
+    }
+
+    pub fn give(&self, chunk: Vec<u8>) {
+        let mut chunks = self.chunks.lock().unwrap();
+
+        if chunks.len() < MAX_RECYCLED_CHUNKS {
+            chunks.push(chunk);
+        }
+    }
+}
+
+// Single producer, single consumer ring of frames in the output format. Player threads write one at a time,
+// JS reads the same memory through an `ArrayBuffer` and releases what it has read.
+// The cursors count frames since the start and only ever grow, so `write - read` is the fill level.
+pub struct AudioRing {
+    storage: AtomicPtr<u8>,
+    // Whatever keeps `storage` alive, only let go of along with the ring, once no writer is left
+    storage_owner: Mutex<Option<Box<dyn Send>>>,
+    // In frames
+    capacity: usize,
+    frame_bytes: usize,
+    write: AtomicUsize,
+    read: AtomicUsize,
+    closed: AtomicBool,
+    // Held while copying, so `close` can't return while the storage is still being written to
+    gate: Mutex<()>,
+    // Only locked by the writer to sleep while the ring is full, and by whoever wakes it up
+    space: (Mutex<()>, Condvar)
+}
+
+impl AudioRing {
+    pub fn new(frames: u64, frame_bytes: usize) -> AudioRing {
+        AudioRing {
+            storage: AtomicPtr::new(ptr::null_mut()),
+            storage_owner: Mutex::new(None),
+            capacity: frames as usize,
+            frame_bytes,
+            write: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            gate: Mutex::new(()),
+            space: (Mutex::new(()), Condvar::new())
+        }
+    }
+
+    pub fn byte_len(&self) -> usize {
+        self.capacity * self.frame_bytes
+    }
+
+    // Hands the ring its memory, along with its `owner`. Writes wait until this has happened.
+    // Unsafe because `storage` has to hold `byte_len` bytes and stay valid for as long as `owner` lives.
+    pub unsafe fn attach(&self, storage: *mut u8, owner: Box<dyn Send>) {
+        *self.storage_owner.lock().unwrap() = Some(owner);
+
+        self.storage.store(storage, Ordering::SeqCst);
+
+        self.notify();
+    }
+
+    // Writes the interleaved stereo `data` into the ring, blocking while it is full. `encode` converts
+    // the frames of each region into its bytes, `emit` is then told its offset and length, in frames.
+    // Gives up once `retired` is set, see `retire`.
+    pub fn write<F, E>(&self, data: &[i16], retired: &AtomicBool, mut encode: F, mut emit: E) where F: FnMut(&[i16], &mut [u8]), E: FnMut(usize, usize) {
+        let mut remaining = data;
+
+        while !remaining.is_empty() {
+            let free = match self.wait_for_space(retired) {
+                Some(free) => free,
+                None => return
+            };
+
+            let write = self.write.load(Ordering::Relaxed);
+            let offset = write % self.capacity;
+
+            let len = cmp::min(cmp::min(remaining.len() / INPUT_CHANNELS, free), self.capacity - offset);
+
+            {
+                let _gate = self.gate.lock().unwrap();
+
+                if self.closed.load(Ordering::SeqCst) {
+                    return;
+                }
+
+                let storage = self.storage.load(Ordering::SeqCst);
+
+                // Only the writer touches [write, read + capacity), JS never reads it before `emit`
+                let region = unsafe { slice::from_raw_parts_mut(storage.add(offset * self.frame_bytes), len * self.frame_bytes) };
+
+                encode(&remaining[..len * INPUT_CHANNELS], region);
+            }
+
+            self.write.store(write + len, Ordering::Release);
+
+            emit(offset, len);
+
+            remaining = &remaining[len * INPUT_CHANNELS..];
+        }
+    }
+
+    // The consumer is done with the next `frames` frames
+    pub fn release(&self, frames: u64) {
+        let write = self.write.load(Ordering::Acquire);
+        let read = self.read.load(Ordering::Relaxed);
+
+        self.read.store(cmp::min(write, read + frames as usize), Ordering::Release);
+
+        self.notify();
+    }
+
+    // Releases the writer of a replaced player for good, the ring stays open for the next one
+    pub fn retire(&self, retired: &AtomicBool) {
+        let (ref lock, ref cvar) = self.space;
+
+        let _guard = lock.lock().unwrap();
+
+        retired.store(true, Ordering::SeqCst);
+
+        cvar.notify_all();
+    }
+
+    // Stops writing for good and releases a blocked writer
+    pub fn close(&self) {
+        let _gate = self.gate.lock().unwrap();
+
+        self.closed.store(true, Ordering::SeqCst);
+
+        self.notify();
+    }
+
+    // Free frames, or None once closed or retired. The lock is only taken once the ring is full,
+    // to check again before going to sleep. `notify` takes it as well, so no wakeup slips in between.
+    fn wait_for_space(&self, retired: &AtomicBool) -> Option<usize> {
+        let (ref lock, ref cvar) = self.space;
+
+        let mut guard = None;
+
+        loop {
+            if self.closed.load(Ordering::SeqCst) || retired.load(Ordering::SeqCst) {
+                return None;
+            }
+
+            if !self.storage.load(Ordering::SeqCst).is_null() {
+                let used = self.write.load(Ordering::Relaxed) - self.read.load(Ordering::Acquire);
+                let free = self.capacity - used;
+
+                if free > 0 {
+                    return Some(free);
+                }
+            }
+
+            guard = match guard {
+                Some(guard) => Some(cvar.wait(guard).unwrap()),
+                None => Some(lock.lock().unwrap())
+            };
+        }
+    }
+
+    fn notify(&self) {
+        let (ref lock, ref cvar) = self.space;
+
+        let _guard = lock.lock().unwrap();
+
+        cvar.notify_all();
+    }
+}
@@ -0,0 +1,267 @@
+use std::{ fmt, mem, thread };
+use std::sync::{ Arc, Condvar, Mutex };
+use std::time::{ Duration, Instant };
+
+use librespot::core::spotify_id::SpotifyId;
+use librespot::playback::player::PlayerEvent;
+
+use neon::context::{ Context, TaskContext };
+use neon::handle::Handle;
+use neon::object::Object;
+use neon::result::NeonResult;
+use neon::types::{ JsFunction, JsValue };
+
+use super::errors::{ ErrorCode, error_to_js };
+
+// The player event a command is done on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Expected {
+    // Playing or paused with this play request id, depending on how it was loaded
+    Load(u64),
+    Playing,
+    Paused,
+    Stopped,
+    // Playing or paused, whichever reports the new position after a seek
+    Position
+}
+
+#[derive(Debug, Clone)]
+pub struct CommandOutcome {
+    pub play_request_id: u64,
+    pub track_id: SpotifyId,
+    pub position_ms: u32,
+    pub duration_ms: u32
+}
+
+#[derive(Debug, Clone)]
+pub enum CommandError {
+    Unavailable,
+    Interrupted,
+    SessionLost,
+    Timeout,
+    Closed
+}
+
+impl ErrorCode for CommandError {
+    fn code(&self) -> &'static str {
+        match self {
+            CommandError::Unavailable => "UNAVAILABLE",
+            CommandError::Interrupted => "INTERRUPTED",
+            CommandError::SessionLost => "SESSION_LOST",
+            CommandError::Timeout => "TIMEOUT",
+            CommandError::Closed => "CLOSED"
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Unavailable => write!(f, "Track is unavailable"),
+            CommandError::Interrupted => write!(f, "Track was stopped before it started"),
+            CommandError::SessionLost => write!(f, "Session was lost"),
+            CommandError::Timeout => write!(f, "Timed out waiting for the player"),
+            CommandError::Closed => write!(f, "Player was shut down")
+        }
+    }
+}
+
+pub type CommandResult = Result<CommandOutcome, CommandError>;
+
+// How long the timeout thread sleeps while no command is pending
+const IDLE_INTERVAL: Duration = Duration::from_secs(1);
+
+enum Slot {
+    Pending,
+    // Settled before anyone asked for the outcome
+    Settled(CommandResult),
+    Waiting(Box<dyn FnOnce(CommandResult) + Send>)
+}
+
+fn settle(slot: &Mutex<Slot>, result: CommandResult) {
+    let mut slot = slot.lock().unwrap();
+
+    match mem::replace(&mut *slot, Slot::Pending) {
+        Slot::Waiting(settle) => {
+            drop(slot);
+
+            settle(result);
+        },
+        _ => *slot = Slot::Settled(result)
+    }
+}
+
+// The outcome of a single command
+pub struct CommandWaiter(Arc<Mutex<Slot>>);
+
+impl CommandWaiter {
+    // Hands the outcome to `settle` once the player confirms, or right away if it already has.
+    // Runs on whichever thread settles the command, so it mustn't block.
+    pub fn settle_with<F>(self, settle: F) where F: FnOnce(CommandResult) + Send + 'static {
+        let mut slot = self.0.lock().unwrap();
+
+        match mem::replace(&mut *slot, Slot::Pending) {
+            Slot::Settled(result) => {
+                drop(slot);
+
+                settle(result);
+            },
+            _ => *slot = Slot::Waiting(Box::new(settle))
+        }
+    }
+}
+
+struct Waiter {
+    expected: Expected,
+    deadline: Instant,
+    slot: Arc<Mutex<Slot>>
+}
+
+// Commands waiting for their player event. Registered before the command is issued,
+// so the event can't come in first.
+pub struct PendingCommands {
+    waiters: Vec<Waiter>,
+    timeout: Duration,
+    // Wakes the timeout thread, which may be sleeping past the deadline of a new waiter
+    registered: Arc<Condvar>
+}
+
+impl PendingCommands {
+    pub fn new(timeout: Duration) -> PendingCommands {
+        PendingCommands {
+            waiters: Vec::new(),
+            timeout,
+            registered: Arc::new(Condvar::new())
+        }
+    }
+
+    pub fn register(&mut self, expected: Expected) -> CommandWaiter {
+        let slot = Arc::new(Mutex::new(Slot::Pending));
+
+        self.waiters.push(Waiter {
+            expected,
+            deadline: Instant::now() + self.timeout,
+            slot: Arc::clone(&slot)
+        });
+
+        self.registered.notify_one();
+
+        CommandWaiter(slot)
+    }
+
+    // Settles every waiter the event is meant for
+    pub fn handle(&mut self, event: &PlayerEvent) {
+        let settle_with = |expected: &Expected| -> Option<CommandResult> {
+            match *event {
+                // A newer load replaces the one that is still loading
+                PlayerEvent::Loading { play_request_id, .. } => match *expected {
+                    Expected::Load(id) if id < play_request_id => Some(Err(CommandError::Interrupted)),
+                    _ => None
+                },
+                PlayerEvent::Playing { play_request_id, track_id, position_ms, duration_ms } => match *expected {
+                    Expected::Load(id) if id != play_request_id => None,
+                    Expected::Load(_) | Expected::Playing | Expected::Position => Some(Ok(CommandOutcome { play_request_id, track_id, position_ms, duration_ms })),
+                    _ => None
+                },
+                PlayerEvent::Paused { play_request_id, track_id, position_ms, duration_ms } => match *expected {
+                    Expected::Load(id) if id != play_request_id => None,
+                    Expected::Load(_) | Expected::Paused | Expected::Position => Some(Ok(CommandOutcome { play_request_id, track_id, position_ms, duration_ms })),
+                    _ => None
+                },
+                PlayerEvent::Stopped { play_request_id, track_id } => match *expected {
+                    Expected::Load(id) if id == play_request_id => Some(Err(CommandError::Interrupted)),
+                    Expected::Stopped => Some(Ok(CommandOutcome { play_request_id, track_id, position_ms: 0, duration_ms: 0 })),
+                    _ => None
+                },
+                // Nothing else is going to happen with the track
+                PlayerEvent::Unavailable { play_request_id, .. } => match *expected {
+                    Expected::Load(id) if id == play_request_id => Some(Err(CommandError::Unavailable)),
+                    _ => None
+                },
+                _ => None
+            }
+        };
+
+        self.waiters.retain(|waiter| {
+            match settle_with(&waiter.expected) {
+                Some(result) => {
+                    settle(&waiter.slot, result);
+
+                    false
+                },
+                None => true
+            }
+        });
+    }
+
+    pub fn fail_all(&mut self, error: CommandError) {
+        for waiter in self.waiters.drain(..) {
+            settle(&waiter.slot, Err(error.clone()));
+        }
+    }
+
+    // Fails the waiters whose event didn't come in time, returns when the next one is due
+    fn expire(&mut self, now: Instant) -> Option<Instant> {
+        let (overdue, waiting): (Vec<Waiter>, Vec<Waiter>) = self.waiters.drain(..).partition(|waiter| waiter.deadline <= now);
+
+        self.waiters = waiting;
+
+        for waiter in overdue {
+            settle(&waiter.slot, Err(CommandError::Timeout));
+        }
+
+        self.waiters.iter().map(|waiter| waiter.deadline).min()
+    }
+}
+
+// Times out the commands of `pending` from its own thread. Exits once the player internals are gone.
+pub fn expire_overdue(pending: &Arc<Mutex<PendingCommands>>) {
+    let registered = Arc::clone(&pending.lock().unwrap().registered);
+    let pending = Arc::downgrade(pending);
+
+    thread::spawn(move || loop {
+        let strong = match pending.upgrade() {
+            Some(strong) => strong,
+            None => return
+        };
+
+        let mut commands = strong.lock().unwrap();
+
+        let now = Instant::now();
+        let wait = commands.expire(now).map_or(IDLE_INTERVAL, |due| due - now);
+
+        let _ = registered.wait_timeout(commands, wait).unwrap();
+    });
+}
+
+// Calls the node style `callback` with the outcome, or with an error carrying its `code`
+pub fn call_back(cx: &mut TaskContext, this: Handle<JsValue>, callback: Handle<JsFunction>, result: CommandResult) -> NeonResult<()> {
+    let outcome = match result {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            let err = error_to_js(cx, &e)?;
+
+            callback.call(cx, this, vec![err])?;
+
+            return Ok(());
+        }
+    };
+
+    let o = cx.empty_object();
+
+    let request = cx.number(outcome.play_request_id as f64);
+    let track = cx.string(outcome.track_id.to_base62());
+    let position = cx.number(outcome.position_ms);
+    let duration = cx.number(outcome.duration_ms);
+
+    o.set(cx, "playRequestId", request)?;
+    o.set(cx, "trackId", track)?;
+    o.set(cx, "positionMs", position)?;
+    o.set(cx, "durationMs", duration)?;
+
+    let null = cx.null();
+
+    callback.call(cx, this, vec![null.upcast(), o.upcast::<JsValue>()])?;
+
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use tokio_core::reactor::Core;
+
+use futures::{ Future, Stream };
+use futures::sync::oneshot;
+use std::{ io, thread };
+use std::sync::mpsc;
+
+use librespot::core::authentication::Credentials;
+use librespot::core::config::ConnectConfig;
+use librespot::connect::discovery::discovery;
+
+// Zeroconf discovery running on its own `Core` thread: the mDNS announcement plus the HTTP
+// `addUser` endpoint phones on the LAN use to hand over credentials. Stopped once dropped.
+pub struct Discovery {
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>
+}
+
+impl Discovery {
+    // Credentials from every `addUser` call arrive on the returned receiver, which
+    // disconnects once discovery is stopped
+    pub fn start(config: ConnectConfig, device_id: String, port: u16) -> io::Result<(Discovery, mpsc::Receiver<Credentials>)> {
+        let (started_tx, started_rx) = mpsc::channel();
+        let (credentials_tx, credentials_rx) = mpsc::channel();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        let thread = thread::spawn(move || {
+            let mut core = Core::new().unwrap();
+
+            let handle = core.handle();
+
+            let stream = match discovery(&handle, config, device_id, port) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    let _ = started_tx.send(Err(e));
+                    return;
+                }
+            };
+
+            let _ = started_tx.send(Ok(()));
+
+            let credentials = stream.for_each(move |credentials| {
+                info!("Discovery login from {}", credentials.username);
+
+                let _ = credentials_tx.send(credentials);
+
+                Ok(())
+            });
+
+            let _ = core.run(credentials.select2(shutdown_rx));
+        });
+
+        match started_rx.recv() {
+            Ok(Ok(())) => Ok((Discovery {
+                shutdown_tx: Some(shutdown_tx),
+                thread: Some(thread)
+            }, credentials_rx)),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Discovery thread exited unexpectedly"))
+        }
+    }
+
+    pub fn shutdown(&mut self) {
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            let _ = shutdown_tx.send(());
+        }
+
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Discovery thread panicked during shutdown");
+            }
+        }
+    }
+}
+
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
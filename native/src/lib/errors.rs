@@ -0,0 +1,20 @@
+use std::fmt::Display;
+
+use neon::context::Context;
+use neon::object::Object;
+use neon::result::JsResult;
+use neon::types::JsError;
+
+// Errors handed to JS along with a machine readable code, set as `error.code`
+pub trait ErrorCode: Display {
+    fn code(&self) -> &'static str;
+}
+
+pub fn error_to_js<'a, C: Context<'a>, E: ErrorCode>(cx: &mut C, e: &E) -> JsResult<'a, JsError> {
+    let err = JsError::error(cx, e.to_string())?;
+    let code = cx.string(e.code());
+
+    err.set(cx, "code", code)?;
+
+    Ok(err)
+}
@@ -1,32 +1,158 @@
 // https://github.com/neon-bindings/examples/blob/master/event-emitter/native/src/lib.rs
 use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
+use std::thread;
 
+use librespot::core::authentication::Credentials;
+use librespot::core::spotify_id::SpotifyId;
 use librespot::playback::player::PlayerEvent;
 
 use neon::context::{Context, TaskContext};
+use neon::event::EventHandler;
 use neon::object::Object;
-use neon::result::JsResult;
+use neon::handle::Handle;
+use neon::result::{JsResult, NeonResult};
 use neon::task::Task;
-use neon::types::{JsUndefined, JsValue, JsBuffer};
+use neon::types::{JsArray, JsValue, JsBuffer, JsObject};
 
 extern crate hex_slice;
+extern crate base64;
+
+use super::session::auth_type_name;
+use super::audio::ChunkPool;
+use super::queue::QueueSnapshot;
+use super::replay_gain::ReplayGain;
+use super::format::OutputFormat;
 
 
 pub enum Event {
   AudioData { 
-    data: Vec<i16>
+    // In the output format
+    data: Vec<u8>,
+    // Where `data` goes back to once it has been copied into a `Buffer`
+    pool: Option<ChunkPool>
+  },
+  // Audio written into the shared ring, in frames
+  AudioRing {
+    offset: usize,
+    frames: usize
+  },
+  // Describes the audio, sent ahead of the first chunk
+  Format {
+    format: OutputFormat,
+    sample_rate: u32
   },
   PlayerStateChange {
-    e: PlayerEvent
+    e: PlayerEvent,
+    // Only for `Playing`, when the gain of the track is known by then
+    replay_gain: Option<ReplayGain>
+  },
+  // The gain of a track whose `Playing` went out before it was known
+  ReplayGain {
+    play_request_id: u64,
+    track_id: SpotifyId,
+    replay_gain: ReplayGain
+  },
+  Credentials {
+    credentials: Credentials
+  },
+  SessionLost,
+  Reconnecting {
+    attempt: u32,
+    delay: Duration
+  },
+  Reconnected,
+  UserConnected {
+    username: String
+  },
+  Position {
+    track_id: SpotifyId,
+    position_ms: u32,
+    duration_ms: u32
+  },
+  QueueChanged {
+    queue: QueueSnapshot
+  },
+  // The direct player has fetched the whole file of a preloaded track
+  Preloaded {
+    track_id: SpotifyId
   }
 }
 
-pub struct EventEmitterTask(pub Arc<Mutex<mpsc::Receiver<Event>>>);
+// Upper bound on the events returned by a single `poll`, unless the caller passes its own
+pub const DEFAULT_POLL_BATCH: usize = 256;
+
+// An event together with the position of the audio stream, in frames, at the time it was emitted.
+// For audio data this is the offset of its first frame.
+pub struct StampedEvent {
+  pub event: Event,
+  pub sample_offset: u64
+}
+
+// Routes audio data and control events to separate channels, so state changes never queue
+// behind PCM. Control events are stamped with the number of frames delivered before them.
+#[derive(Clone)]
+pub struct EventSender {
+  control: mpsc::Sender<StampedEvent>,
+  audio: mpsc::Sender<StampedEvent>,
+  frames: Arc<AtomicU64>
+}
+
+impl EventSender {
+  pub fn channel() -> (EventSender, EventEmitter) {
+    let (control_tx, control_rx) = mpsc::channel();
+    let (audio_tx, audio_rx) = mpsc::channel();
+
+    let sender = EventSender {
+      control: control_tx,
+      audio: audio_tx,
+      frames: Arc::new(AtomicU64::new(0))
+    };
+
+    let emitter = EventEmitter {
+      events: Arc::new(Mutex::new(control_rx)),
+      audio: Arc::new(Mutex::new(audio_rx))
+    };
+
+    (sender, emitter)
+  }
+
+  pub fn send(&self, event: Event) -> Result<(), mpsc::SendError<Event>> {
+    self.send_at(event, self.frames())
+  }
+
+  // For an event that happened at `sample_offset` but is sent later
+  pub fn send_at(&self, event: Event, sample_offset: u64) -> Result<(), mpsc::SendError<Event>> {
+    let stamped = StampedEvent {
+      event,
+      sample_offset
+    };
+
+    let result = match stamped.event {
+      Event::AudioData { .. } | Event::AudioRing { .. } | Event::Format { .. } => self.audio.send(stamped),
+      _ => self.control.send(stamped)
+    };
+
+    result.map_err(|mpsc::SendError(stamped)| mpsc::SendError(stamped.event))
+  }
+
+  // Frames delivered so far
+  pub fn frames(&self) -> u64 {
+    self.frames.load(Ordering::SeqCst)
+  }
+
+  // Called by the sink once a chunk of `frames` frames has been handed to the audio channel
+  pub fn advance(&self, frames: u64) {
+    self.frames.fetch_add(frames, Ordering::SeqCst);
+  }
+}
+
+pub struct EventEmitterTask(pub Arc<Mutex<mpsc::Receiver<StampedEvent>>>, pub usize);
 
 impl Task for EventEmitterTask {
-  type Output = Option<Event>;
+  type Output = Vec<StampedEvent>;
   type Error = String;
   type JsEvent = JsValue;
 
@@ -40,155 +166,402 @@ impl Task for EventEmitterTask {
           .lock()
           .map_err(|_| "Could not obtain lock on receiver".to_string())?;
 
+      let mut events = Vec::new();
+
       // Attempt to read from the channel. Block for at most 100 ms.
       match rx.recv_timeout(Duration::from_millis(100)) {
-          Ok(event) => Ok(Some(event)),
-          Err(RecvTimeoutError::Timeout) => Ok(None),
-          Err(RecvTimeoutError::Disconnected) => Err("Failed to receive event".to_string()),
+          Ok(event) => events.push(event),
+          Err(RecvTimeoutError::Timeout) => return Ok(events),
+          Err(RecvTimeoutError::Disconnected) => return Err("Failed to receive event".to_string()),
       }
+
+      // Then drain whatever else is already queued, so a busy audio stream can't outpace polling
+      while events.len() < self.1 {
+          match rx.try_recv() {
+              Ok(event) => events.push(event),
+              Err(_) => break
+          }
+      }
+
+      Ok(events)
   }
 
   // After the `perform` method has returned, the `complete` method is
   // scheduled on the main thread. It is responsible for converting the
-  // Rust data structure into a JS object.
+  // Rust data structures into an array of JS objects.
   fn complete(
       self,
       mut cx: TaskContext,
-      event: Result<Self::Output, Self::Error>,
+      events: Result<Self::Output, Self::Error>,
   ) -> JsResult<Self::JsEvent> {
-      // Receive the event or return early with the error
-      let event = event.or_else(|err| cx.throw_error(&err.to_string()))?;
+      // Receive the events or return early with the error
+      let events = events.or_else(|err| cx.throw_error(&err.to_string()))?;
+
+      // Empty on timeout
+      let array = JsArray::new(&mut cx, events.len() as u32);
+
+      for (i, event) in events.into_iter().enumerate() {
+          let o = event_to_js(&mut cx, event)?;
+
+          array.set(&mut cx, i as u32, o)?;
+      }
+
+      Ok(array.upcast())
+  }
+}
+
+// Converts an event into an object of the shape `{ "name": string, "sampleOffset": number, ...data }`
+pub fn event_to_js<'a, C: Context<'a>>(cx: &mut C, stamped: StampedEvent) -> JsResult<'a, JsObject> {
+  let StampedEvent { event, sample_offset } = stamped;
+
+  // Create an empty object `{}`
+  let o = cx.empty_object();
+
+  let sample_offset = cx.number(sample_offset as f64);
+
+  o.set(cx, "sampleOffset", sample_offset).expect("attribute set");
+
+  // Creates an object of the shape `{ "event": string, ...data }`
+  let event_name;
+
+  match event {
+      Event::AudioData { data, pool } => {
+          event_name = cx.string("audio-data");
+
+          let mut event_data = JsBuffer::new(cx, data.len() as u32).expect("buffer to be allocated");
+
+          cx.borrow_mut(&mut event_data, |d| {
+            d.as_mut_slice::<u8>().copy_from_slice(&data)
+          });
+
+          if let Some(pool) = pool {
+            pool.give(data);
+          }
+
+          o.set(cx, "data", event_data).expect("event data to be set");
+      },
+
+      Event::AudioRing { offset, frames } => {
+          event_name = cx.string("audio-ring");
+
+          let offset = cx.number(offset as f64);
+          let frames = cx.number(frames as f64);
+
+          o.set(cx, "offset", offset).expect("attribute set");
+          o.set(cx, "frames", frames).expect("attribute set");
+      },
+
+      Event::Credentials { credentials } => {
+          event_name = cx.string("credentials");
+
+          let username = cx.string(credentials.username);
+          let auth_type = cx.string(auth_type_name(credentials.auth_type));
+          let auth_data = cx.string(base64::encode(&credentials.auth_data));
+
+          o.set(cx, "username", username).expect("attribute set");
+          o.set(cx, "authType", auth_type).expect("attribute set");
+          o.set(cx, "authData", auth_data).expect("attribute set");
+      },
+
+      Event::SessionLost => {
+          event_name = cx.string("session-lost");
+      },
+
+      Event::Reconnecting { attempt, delay } => {
+          event_name = cx.string("reconnecting");
+
+          let attempt = cx.number(attempt);
+          let delay_ms = cx.number((delay.as_secs() * 1000 + delay.subsec_millis() as u64) as f64);
+
+          o.set(cx, "attempt", attempt).expect("attribute set");
+          o.set(cx, "delayMs", delay_ms).expect("attribute set");
+      },
+
+      Event::Reconnected => {
+          event_name = cx.string("reconnected");
+      },
+
+      Event::UserConnected { username } => {
+          event_name = cx.string("user-connected");
+
+          let username = cx.string(username);
+
+          o.set(cx, "username", username).expect("attribute set");
+      },
+
+      Event::Position { track_id, position_ms, duration_ms } => {
+          event_name = cx.string("position");
+
+          let track = cx.string(track_id.to_base62());
+          let position = cx.number(position_ms);
+          let duration = cx.number(duration_ms);
+
+          o.set(cx, "trackId", track).expect("attribute set");
+          o.set(cx, "positionMs", position).expect("attribute set");
+          o.set(cx, "durationMs", duration).expect("attribute set");
+      },
+
+      Event::Format { format, sample_rate } => {
+          event_name = cx.string("format");
+
+          format_to_js(cx, format, sample_rate, o)?;
+      },
 
-      // Timeout occured, return early with `undefined
-      let event = match event {
-          Some(event) => event,
-          None => return Ok(JsUndefined::new().upcast()),
-      };
+      Event::QueueChanged { queue } => {
+          event_name = cx.string("queue-changed");
 
-      // Create an empty object `{}`
-      let o = cx.empty_object();
+          queue_to_js(cx, queue, o)?;
+      },
 
-      // Creates an object of the shape `{ "event": string, ...data }`
-      let event_name;
+      Event::Preloaded { track_id } => {
+          event_name = cx.string("preloaded");
 
-      match event {
-          Event::AudioData { data } => {
-              event_name = cx.string("audio-data");
+          let track = cx.string(track_id.to_base62());
 
-              let mut event_data = JsBuffer::new(&mut cx, (data.len() as u32) * 2).expect("buffer to be allocated");
+          o.set(cx, "trackId", track).expect("attribute set");
+      },
 
-              cx.borrow_mut(&mut event_data, |d| {
-                d.as_mut_slice::<i16>().copy_from_slice(&data)
-              });
+      Event::ReplayGain { play_request_id, track_id, replay_gain } => {
+          event_name = cx.string("replay-gain");
 
-              o.set(&mut cx, "data", event_data).expect("event data to be set");
+          let request = cx.number(play_request_id as f64);
+          let track = cx.string(track_id.to_base62());
+          let replay_gain = replay_gain_to_js(cx, replay_gain)?;
+
+          o.set(cx, "trackId", track).expect("attribute set");
+          o.set(cx, "playRequestId", request).expect("attribute set");
+          o.set(cx, "replayGain", replay_gain).expect("attribute set");
+      },
+
+      Event::PlayerStateChange { e, replay_gain } => {
+        match e {
+          PlayerEvent::Started { play_request_id, track_id, position_ms } => {
+            event_name = cx.string("started");
+
+            let request = cx.number(play_request_id as f64);
+            let track = cx.string(track_id.to_base62());
+            let position = cx.number(position_ms);
+
+            o.set(cx, "trackId", track).expect("attribute set");
+            o.set(cx, "playRequestId", request).expect("attribute set");
+            o.set(cx, "positionMs", position).expect("attribute set");
           },
 
-          Event::PlayerStateChange { e } => {
-            match e {
-              PlayerEvent::Started { track_id, position_ms, .. } => {
-                event_name = cx.string("started");
-    
-                let track = cx.string(track_id.to_base62());
-                let position = cx.number(position_ms);
-    
-                o.set(&mut cx, "trackId", track).expect("attribute set");
-                o.set(&mut cx, "positionMs", position).expect("attribute set");
-              },
-    
-              PlayerEvent::Stopped { track_id, .. } => {
-                event_name = cx.string("stopped");
-    
-                let track = cx.string(track_id.to_base62());
-    
-                o.set(&mut cx, "trackId", track).expect("attribute set");
-              },
-    
-              PlayerEvent::Changed { old_track_id, new_track_id } => {
-                event_name = cx.string("changed");
-    
-                let old_track = cx.string(old_track_id.to_base62());
-                let new_track = cx.string(new_track_id.to_base62());
-    
-                o.set(&mut cx, "oldTrackId", old_track).expect("attribute set");
-                o.set(&mut cx, "newTrackId", new_track).expect("attribute set");
-              },
-    
-              PlayerEvent::Loading { track_id, position_ms, .. } => {
-                event_name = cx.string("loading");
-    
-                let track = cx.string(track_id.to_base62());
-                let position = cx.number(position_ms);
-    
-                o.set(&mut cx, "trackId", track).expect("attribute set");
-                o.set(&mut cx, "positionMs", position).expect("attribute set");
-              },
-    
-              PlayerEvent::Playing { track_id, position_ms, duration_ms, .. } => {
-                event_name = cx.string("playing");
-    
-                let track = cx.string(track_id.to_base62());
-                let position = cx.number(position_ms);
-                let duration = cx.number(duration_ms);
-    
-                o.set(&mut cx, "trackId", track).expect("attribute set");
-                o.set(&mut cx, "positionMs", position).expect("attribute set");
-                o.set(&mut cx, "durationMs", duration).expect("attribute set");
-              },
-    
-              PlayerEvent::Paused { track_id, position_ms, duration_ms, .. } => {
-                event_name = cx.string("paused");
-    
-                let track = cx.string(track_id.to_base62());
-                let position = cx.number(position_ms);
-                let duration = cx.number(duration_ms);
-    
-                o.set(&mut cx, "trackId", track).expect("attribute set");
-                o.set(&mut cx, "positionMs", position).expect("attribute set");
-                o.set(&mut cx, "durationMs", duration).expect("attribute set");
-              },
-    
-              PlayerEvent::EndOfTrack { track_id, .. } => {
-                event_name = cx.string("end-of-track");
-    
-                let track = cx.string(track_id.to_base62());
-    
-                o.set(&mut cx, "trackId", track).expect("attribute set");
-              },
-    
-              PlayerEvent::VolumeSet { volume } => {
-                event_name = cx.string("volume-set");
-    
-                let volume = cx.number(volume);
-    
-                o.set(&mut cx, "volume", volume).expect("attribute set");
-              },
+          PlayerEvent::Stopped { play_request_id, track_id } => {
+            event_name = cx.string("stopped");
 
-              PlayerEvent::TimeToPreloadNextTrack { track_id, .. } => {
-                event_name = cx.string("time-to-preload-next-track");
+            let request = cx.number(play_request_id as f64);
+            let track = cx.string(track_id.to_base62());
 
-                let track = cx.string(track_id.to_base62());
+            o.set(cx, "trackId", track).expect("attribute set");
+            o.set(cx, "playRequestId", request).expect("attribute set");
+          },
 
-                o.set(&mut cx, "trackId", track).expect("attribute set");
-              },
+          PlayerEvent::Changed { old_track_id, new_track_id } => {
+            event_name = cx.string("changed");
+
+            let old_track = cx.string(old_track_id.to_base62());
+            let new_track = cx.string(new_track_id.to_base62());
+
+            o.set(cx, "oldTrackId", old_track).expect("attribute set");
+            o.set(cx, "newTrackId", new_track).expect("attribute set");
+          },
+
+          PlayerEvent::Loading { play_request_id, track_id, position_ms } => {
+            event_name = cx.string("loading");
 
-              PlayerEvent::Unavailable { track_id, .. } => {
-                event_name = cx.string("unavailable");
+            let request = cx.number(play_request_id as f64);
+            let track = cx.string(track_id.to_base62());
+            let position = cx.number(position_ms);
 
-                let track = cx.string(track_id.to_base62());
+            o.set(cx, "trackId", track).expect("attribute set");
+            o.set(cx, "playRequestId", request).expect("attribute set");
+            o.set(cx, "positionMs", position).expect("attribute set");
+          },
+
+          PlayerEvent::Playing { play_request_id, track_id, position_ms, duration_ms } => {
+            event_name = cx.string("playing");
+
+            let request = cx.number(play_request_id as f64);
+            let track = cx.string(track_id.to_base62());
+            let position = cx.number(position_ms);
+            let duration = cx.number(duration_ms);
+
+            o.set(cx, "trackId", track).expect("attribute set");
+            o.set(cx, "playRequestId", request).expect("attribute set");
+            o.set(cx, "positionMs", position).expect("attribute set");
+            o.set(cx, "durationMs", duration).expect("attribute set");
+
+            match replay_gain {
+              Some(gain) => {
+                let replay_gain = replay_gain_to_js(cx, gain)?;
 
-                o.set(&mut cx, "trackId", track).expect("attribute set");
+                o.set(cx, "replayGain", replay_gain).expect("attribute set");
+              },
+              None => {
+                let none = cx.null();
+
+                o.set(cx, "replayGain", none).expect("attribute set");
               }
             }
+          },
+
+          PlayerEvent::Paused { play_request_id, track_id, position_ms, duration_ms } => {
+            event_name = cx.string("paused");
+
+            let request = cx.number(play_request_id as f64);
+            let track = cx.string(track_id.to_base62());
+            let position = cx.number(position_ms);
+            let duration = cx.number(duration_ms);
+
+            o.set(cx, "trackId", track).expect("attribute set");
+            o.set(cx, "playRequestId", request).expect("attribute set");
+            o.set(cx, "positionMs", position).expect("attribute set");
+            o.set(cx, "durationMs", duration).expect("attribute set");
+          },
+
+          PlayerEvent::EndOfTrack { play_request_id, track_id } => {
+            event_name = cx.string("end-of-track");
+
+            let request = cx.number(play_request_id as f64);
+            let track = cx.string(track_id.to_base62());
+
+            o.set(cx, "trackId", track).expect("attribute set");
+            o.set(cx, "playRequestId", request).expect("attribute set");
+          },
+
+          PlayerEvent::VolumeSet { volume } => {
+            event_name = cx.string("volume-set");
+
+            let volume = cx.number(volume);
+
+            o.set(cx, "volume", volume).expect("attribute set");
+          },
+
+          PlayerEvent::TimeToPreloadNextTrack { play_request_id, track_id } => {
+            event_name = cx.string("time-to-preload-next-track");
+
+            let request = cx.number(play_request_id as f64);
+            let track = cx.string(track_id.to_base62());
+
+            o.set(cx, "trackId", track).expect("attribute set");
+            o.set(cx, "playRequestId", request).expect("attribute set");
+          },
+
+          PlayerEvent::Unavailable { play_request_id, track_id } => {
+            event_name = cx.string("unavailable");
+
+            let request = cx.number(play_request_id as f64);
+            let track = cx.string(track_id.to_base62());
+
+            o.set(cx, "trackId", track).expect("attribute set");
+            o.set(cx, "playRequestId", request).expect("attribute set");
           }
+        }
       }
+  }
+
+  o.set(cx, "name", event_name).expect("event name to be set");
+
+  Ok(o)
+}
+
+fn replay_gain_to_js<'a, C: Context<'a>>(cx: &mut C, gain: ReplayGain) -> JsResult<'a, JsObject> {
+  let o = cx.empty_object();
+
+  let track_gain = cx.number(gain.track_gain_db);
+  let track_peak = cx.number(gain.track_peak);
+  let pregain = cx.number(gain.pregain_db);
+  let factor = cx.number(gain.factor);
+
+  o.set(cx, "trackGainDb", track_gain)?;
+  o.set(cx, "trackPeak", track_peak)?;
+  o.set(cx, "pregainDb", pregain)?;
+  o.set(cx, "factor", factor)?;
+
+  Ok(o)
+}
 
-      o.set(&mut cx, "name", event_name).expect("event name to be set");
+// Sets `tracks`, `current` (null when nothing from the queue is playing), `shuffle` and `repeat` on `o`
+pub fn queue_to_js<'a, C: Context<'a>>(cx: &mut C, queue: QueueSnapshot, o: Handle<'a, JsObject>) -> NeonResult<()> {
+  let tracks = JsArray::new(cx, queue.tracks.len() as u32);
 
-      Ok(o.upcast())
+  for (i, track_id) in queue.tracks.iter().enumerate() {
+    let track = cx.string(track_id.to_base62());
+
+    tracks.set(cx, i as u32, track)?;
   }
+
+  let current: Handle<JsValue> = match queue.current {
+    Some(current) => cx.number(current as f64).upcast(),
+    None => cx.null().upcast()
+  };
+  let shuffle = cx.boolean(queue.shuffle);
+  let repeat = cx.string(queue.repeat.name());
+
+  o.set(cx, "tracks", tracks)?;
+  o.set(cx, "current", current)?;
+  o.set(cx, "shuffle", shuffle)?;
+  o.set(cx, "repeat", repeat)?;
+
+  Ok(())
+}
+
+pub fn format_to_js<'a, C: Context<'a>>(cx: &mut C, format: OutputFormat, sample_rate: u32, o: Handle<'a, JsObject>) -> NeonResult<()> {
+  let sample_format = cx.string(format.sample_format.name());
+  let layout = cx.string(format.layout.name());
+  let channels = cx.number(format.channels.count() as f64);
+  let sample_rate = cx.number(sample_rate);
+  let bytes_per_sample = cx.number(format.sample_format.bytes() as f64);
+  let bytes_per_frame = cx.number(format.bytes_per_frame() as f64);
+
+  o.set(cx, "sampleFormat", sample_format)?;
+  o.set(cx, "layout", layout)?;
+  o.set(cx, "channels", channels)?;
+  o.set(cx, "sampleRate", sample_rate)?;
+  o.set(cx, "bytesPerSample", bytes_per_sample)?;
+  o.set(cx, "bytesPerFrame", bytes_per_frame)?;
+
+  Ok(())
 }
 
 pub struct EventEmitter {
-  pub events: Arc<Mutex<mpsc::Receiver<Event>>>
+  pub events: Arc<Mutex<mpsc::Receiver<StampedEvent>>>,
+  pub audio: Arc<Mutex<mpsc::Receiver<StampedEvent>>>
+}
+
+impl EventEmitter {
+  // Pushes every control event to the JS callback from a dedicated thread, instead of waiting to be polled
+  pub fn listen(&self, handler: EventHandler) {
+    listen_on(Arc::clone(&self.events), handler);
+  }
+
+  // Same for audio data, through its own thread and callback
+  pub fn listen_audio(&self, handler: EventHandler) {
+    listen_on(Arc::clone(&self.audio), handler);
+  }
+}
+
+// The thread keeps the receiver locked and exits once the channel closes on shutdown
+fn listen_on(events: Arc<Mutex<mpsc::Receiver<StampedEvent>>>, handler: EventHandler) {
+  thread::spawn(move || {
+    let rx = match events.lock() {
+      Ok(rx) => rx,
+      Err(_) => {
+        error!("Could not obtain lock on receiver");
+        return;
+      }
+    };
+
+    for event in rx.iter() {
+      // A failed conversion leaves its exception pending, for Node to report
+      handler.schedule_with(move |cx, this, callback| {
+        if let Ok(o) = event_to_js(cx, event) {
+          let _ = callback.call(cx, this, vec![o]);
+        }
+      });
+    }
+
+    debug!("Event channel closed, stopped listening");
+  });
 }
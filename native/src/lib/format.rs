@@ -0,0 +1,248 @@
+use std::str::FromStr;
+
+// Frames come out of the decoder as interleaved stereo
+const INPUT_CHANNELS: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleFormat {
+    S16Le,
+    // Packed into three bytes
+    S24Le,
+    S32Le,
+    // -1.0 to 1.0
+    F32Le
+}
+
+impl SampleFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SampleFormat::S16Le => "s16le",
+            SampleFormat::S24Le => "s24le",
+            SampleFormat::S32Le => "s32le",
+            SampleFormat::F32Le => "f32le"
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        match self {
+            SampleFormat::S16Le => 2,
+            SampleFormat::S24Le => 3,
+            SampleFormat::S32Le | SampleFormat::F32Le => 4
+        }
+    }
+
+    fn write(&self, sample: i16, out: &mut [u8]) {
+        match self {
+            SampleFormat::S16Le => out.copy_from_slice(&sample.to_le_bytes()),
+            SampleFormat::S24Le => out.copy_from_slice(&((sample as i32) << 8).to_le_bytes()[..3]),
+            SampleFormat::S32Le => out.copy_from_slice(&((sample as i32) << 16).to_le_bytes()),
+            SampleFormat::F32Le => out.copy_from_slice(&(sample as f32 / 32768.0).to_bits().to_le_bytes())
+        }
+    }
+}
+
+impl FromStr for SampleFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "s16le" => Ok(SampleFormat::S16Le),
+            "s24le" => Ok(SampleFormat::S24Le),
+            "s32le" => Ok(SampleFormat::S32Le),
+            "f32le" => Ok(SampleFormat::F32Le),
+            _ => Err(())
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelLayout {
+    Interleaved,
+    // Each chunk, or ring region, holds all of its left samples, then all of its right ones
+    Planar
+}
+
+impl ChannelLayout {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChannelLayout::Interleaved => "interleaved",
+            ChannelLayout::Planar => "planar"
+        }
+    }
+}
+
+impl FromStr for ChannelLayout {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interleaved" => Ok(ChannelLayout::Interleaved),
+            "planar" => Ok(ChannelLayout::Planar),
+            _ => Err(())
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channels {
+    Stereo,
+    // Both channels averaged
+    Mono
+}
+
+impl Channels {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channels::Stereo => "stereo",
+            Channels::Mono => "mono"
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        match self {
+            Channels::Stereo => 2,
+            Channels::Mono => 1
+        }
+    }
+}
+
+impl FromStr for Channels {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stereo" => Ok(Channels::Stereo),
+            "mono" => Ok(Channels::Mono),
+            _ => Err(())
+        }
+    }
+}
+
+// What the audio handed to JS looks like. Frames are counted in this format everywhere,
+// a frame being one sample per output channel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputFormat {
+    pub sample_format: SampleFormat,
+    pub layout: ChannelLayout,
+    pub channels: Channels
+}
+
+impl Default for OutputFormat {
+    // What librespot decodes to, handed out as is
+    fn default() -> OutputFormat {
+        OutputFormat {
+            sample_format: SampleFormat::S16Le,
+            layout: ChannelLayout::Interleaved,
+            channels: Channels::Stereo
+        }
+    }
+}
+
+impl OutputFormat {
+    pub fn bytes_per_frame(&self) -> usize {
+        self.sample_format.bytes() * self.channels.count()
+    }
+
+    // Converts interleaved stereo `samples` into `out`, which holds exactly as many frames
+    pub fn encode(&self, samples: &[i16], out: &mut [u8]) {
+        let frames = samples.len() / INPUT_CHANNELS;
+        let bytes = self.sample_format.bytes();
+        let channels = self.channels.count();
+
+        for (frame, input) in samples.chunks(INPUT_CHANNELS).enumerate() {
+            for channel in 0..channels {
+                let sample = match self.channels {
+                    Channels::Stereo => input[channel],
+                    Channels::Mono => ((input[0] as i32 + input[1] as i32) / 2) as i16
+                };
+
+                let index = match self.layout {
+                    ChannelLayout::Interleaved => frame * channels + channel,
+                    ChannelLayout::Planar => channel * frames + frame
+                };
+
+                self.sample_format.write(sample, &mut out[index * bytes..(index + 1) * bytes]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(sample_format: SampleFormat, layout: ChannelLayout, channels: Channels) -> OutputFormat {
+        OutputFormat { sample_format, layout, channels }
+    }
+
+    fn encode(format: OutputFormat, samples: &[i16]) -> Vec<u8> {
+        let mut out = vec![0; samples.len() / INPUT_CHANNELS * format.bytes_per_frame()];
+
+        format.encode(samples, &mut out);
+
+        out
+    }
+
+    #[test]
+    fn default_is_the_decoder_output() {
+        let samples = [1, -2, 0x1234, i16::min_value()];
+
+        let expected: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes().to_vec()).collect();
+
+        assert_eq!(encode(OutputFormat::default(), &samples), expected);
+    }
+
+    #[test]
+    fn packs_s24_into_three_bytes() {
+        let s24 = format(SampleFormat::S24Le, ChannelLayout::Interleaved, Channels::Stereo);
+
+        assert_eq!(s24.bytes_per_frame(), 6);
+        assert_eq!(encode(s24, &[0x1234, -1]), vec![0x00, 0x34, 0x12, 0x00, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn scales_to_the_wider_formats() {
+        let s32 = format(SampleFormat::S32Le, ChannelLayout::Interleaved, Channels::Stereo);
+        let float = format(SampleFormat::F32Le, ChannelLayout::Interleaved, Channels::Stereo);
+
+        assert_eq!(encode(s32, &[0x1234, i16::min_value()]), vec![0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x80]);
+
+        let out = encode(float, &[i16::min_value(), 16384]);
+
+        assert_eq!(f32::from_le_bytes([out[0], out[1], out[2], out[3]]), -1.0);
+        assert_eq!(f32::from_le_bytes([out[4], out[5], out[6], out[7]]), 0.5);
+    }
+
+    #[test]
+    fn planar_groups_each_channel() {
+        let planar = format(SampleFormat::S16Le, ChannelLayout::Planar, Channels::Stereo);
+
+        let out = encode(planar, &[1, 2, 3, 4, 5, 6]);
+
+        assert_eq!(out, vec![1, 0, 3, 0, 5, 0, 2, 0, 4, 0, 6, 0]);
+    }
+
+    #[test]
+    fn mono_averages_both_channels() {
+        let mono = format(SampleFormat::S16Le, ChannelLayout::Interleaved, Channels::Mono);
+
+        assert_eq!(mono.bytes_per_frame(), 2);
+        assert_eq!(encode(mono, &[10, 20, i16::max_value(), i16::max_value()]), vec![15, 0, 0xff, 0x7f]);
+
+        // One channel is the same either way
+        let planar = format(SampleFormat::S16Le, ChannelLayout::Planar, Channels::Mono);
+
+        assert_eq!(encode(planar, &[10, 20, 30, 40]), vec![15, 0, 35, 0]);
+    }
+
+    #[test]
+    fn parses_option_names() {
+        for sample_format in [SampleFormat::S16Le, SampleFormat::S24Le, SampleFormat::S32Le, SampleFormat::F32Le].iter() {
+            assert_eq!(sample_format.name().parse(), Ok(*sample_format));
+        }
+
+        assert_eq!("planar".parse(), Ok(ChannelLayout::Planar));
+        assert_eq!("mono".parse(), Ok(Channels::Mono));
+        assert_eq!("s16be".parse::<SampleFormat>(), Err(()));
+    }
+}
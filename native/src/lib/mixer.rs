@@ -0,0 +1,89 @@
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+
+use librespot::core::config::VolumeCtrl;
+use librespot::playback::mixer::{ Mixer, AudioFilter, MixerConfig };
+
+const MAX_VOLUME: u16 = std::u16::MAX;
+
+// Software volume shared by the Connect device and direct playback.
+// Spirc gets a clone as its `Mixer`, `EmittedSink` scales the PCM with it.
+#[derive(Clone)]
+pub struct SoftwareMixer {
+    volume: Arc<AtomicUsize>,
+    // The volume as set, before the curve. Spirc only hands the mixer the level, so this follows `VolumeSet` events.
+    requested: Arc<AtomicUsize>
+}
+
+impl SoftwareMixer {
+    pub fn new(requested: u16, volume_ctrl: &VolumeCtrl) -> SoftwareMixer {
+        SoftwareMixer {
+            volume: Arc::new(AtomicUsize::new(volume_to_mixer(requested, volume_ctrl) as usize)),
+            requested: Arc::new(AtomicUsize::new(requested as usize))
+        }
+    }
+
+    // Same scale as `VolumeSet` events, 0-65535 before the curve
+    pub fn requested_volume(&self) -> u16 {
+        self.requested.load(Ordering::Relaxed) as u16
+    }
+
+    pub fn set_requested_volume(&self, volume: u16) {
+        self.requested.store(volume as usize, Ordering::Relaxed);
+    }
+
+    pub fn apply(&self, samples: &mut [i16]) {
+        let volume = self.volume.load(Ordering::Relaxed) as i32;
+
+        if volume == MAX_VOLUME as i32 {
+            return;
+        }
+
+        for sample in samples.iter_mut() {
+            *sample = ((*sample as i32 * volume) / MAX_VOLUME as i32) as i16;
+        }
+    }
+}
+
+impl Mixer for SoftwareMixer {
+    fn open(_config: Option<MixerConfig>) -> SoftwareMixer {
+        SoftwareMixer::new(MAX_VOLUME, &VolumeCtrl::Linear)
+    }
+
+    fn start(&self) {}
+
+    fn stop(&self) {}
+
+    fn volume(&self) -> u16 {
+        self.volume.load(Ordering::Relaxed) as u16
+    }
+
+    fn set_volume(&self, volume: u16) {
+        self.volume.store(volume as usize, Ordering::Relaxed);
+    }
+
+    fn get_audio_filter(&self) -> Option<Box<dyn AudioFilter + Send>> {
+        None
+    }
+}
+
+// Maps a 0-65535 volume to the mixer level, the same way Spirc does for the Connect slider.
+// `Fixed` keeps the output at full volume.
+pub fn volume_to_mixer(volume: u16, volume_ctrl: &VolumeCtrl) -> u16 {
+    match volume_ctrl {
+        VolumeCtrl::Linear => volume,
+        VolumeCtrl::Log => {
+            // https://www.dr-lex.be/info-stuff/volumecontrols.html#ideal2
+            const IDEAL_FACTOR: f64 = 6.908;
+
+            let normalized_volume = volume as f64 / MAX_VOLUME as f64;
+
+            if normalized_volume < 0.999 {
+                ((normalized_volume * IDEAL_FACTOR).exp() / 1000.0 * MAX_VOLUME as f64) as u16
+            } else {
+                MAX_VOLUME
+            }
+        },
+        VolumeCtrl::Fixed => MAX_VOLUME
+    }
+}
@@ -0,0 +1,178 @@
+use std::cmp;
+use std::str::FromStr;
+use std::sync::{ Arc, Condvar, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+// What librespot decodes to, the sink may resample it
+pub const DECODER_SAMPLE_RATE: u64 = 44100;
+
+// How far real-time pacing lets the sink run ahead of the wall clock
+const REALTIME_LEAD: Duration = Duration::from_millis(200);
+// Falling further behind than this (a stalled consumer, a slow network) restarts the clock
+// instead of bursting to catch up
+const REALTIME_MAX_LAG: Duration = Duration::from_secs(1);
+
+// How the sink paces decoding
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pacing {
+    // Blocks to match the wall clock at the output sample rate
+    Realtime,
+    // Blocks once `buffer` frames are waiting to be acknowledged by the consumer
+    Consumer { buffer: u64 },
+    // Renders as fast as the CPU allows, for offline processing
+    Free
+}
+
+impl FromStr for Pacing {
+    type Err = ();
+
+    // Consumer mode defaults to a one second buffer at the decoder rate, see `PlayerOptions`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "realtime" => Ok(Pacing::Realtime),
+            "consumer" => Ok(Pacing::Consumer { buffer: DECODER_SAMPLE_RATE }),
+            "free" => Ok(Pacing::Free),
+            _ => Err(())
+        }
+    }
+}
+
+struct State {
+    // Start of the current run and the frames written since, for real-time pacing
+    clock: Option<(Instant, u64)>,
+    // Frames handed out but not acknowledged yet, for consumer pacing
+    in_flight: u64,
+    closed: bool
+}
+
+// Shared by every clone of the sink, so both players and the JS side see the same clock and buffer.
+// Each sink passes its own `retired` flag, set once its player is replaced.
+#[derive(Clone)]
+pub struct Pacer {
+    pacing: Pacing,
+    // Of the frames passed to `wait`
+    sample_rate: u64,
+    state: Arc<(Mutex<State>, Condvar)>
+}
+
+impl Pacer {
+    pub fn new(pacing: Pacing, sample_rate: u64) -> Pacer {
+        Pacer {
+            pacing,
+            sample_rate,
+            state: Arc::new((Mutex::new(State { clock: None, in_flight: 0, closed: false }), Condvar::new()))
+        }
+    }
+
+    // Called when the sink starts, playback resumes from here
+    pub fn restart(&self) {
+        let (ref lock, _) = *self.state;
+
+        lock.lock().unwrap().clock = None;
+    }
+
+    // Blocks the player thread until `frames` more frames may be written
+    pub fn wait(&self, frames: u64, retired: &AtomicBool) {
+        match self.pacing {
+            Pacing::Realtime => self.wait_realtime(frames),
+            Pacing::Consumer { buffer } => self.wait_consumer(frames, buffer, retired),
+            Pacing::Free => {}
+        }
+    }
+
+    fn wait_realtime(&self, frames: u64) {
+        let deadline = {
+            let (ref lock, _) = *self.state;
+            let mut state = lock.lock().unwrap();
+
+            if state.closed {
+                return;
+            }
+
+            let now = Instant::now();
+
+            let (started_at, written) = match state.clock {
+                Some((started_at, written)) if now < started_at + frames_duration(written, self.sample_rate) + REALTIME_MAX_LAG => (started_at, written),
+                _ => (now, 0)
+            };
+
+            state.clock = Some((started_at, written + frames));
+
+            started_at + frames_duration(written, self.sample_rate)
+        };
+
+        let now = Instant::now();
+
+        if deadline > now + REALTIME_LEAD {
+            thread::sleep(deadline - now - REALTIME_LEAD);
+        }
+    }
+
+    // A chunk larger than the whole buffer is let through once nothing else is in flight
+    fn wait_consumer(&self, frames: u64, buffer: u64, retired: &AtomicBool) {
+        let (ref lock, ref cvar) = *self.state;
+        let mut state = lock.lock().unwrap();
+
+        let done = |state: &State| state.closed || retired.load(Ordering::SeqCst);
+
+        while !done(&state) && state.in_flight > 0 && state.in_flight + frames > buffer {
+            state = cvar.wait(state).unwrap();
+        }
+
+        if !done(&state) {
+            state.in_flight += frames;
+        }
+    }
+
+    // The consumer has read `frames` frames
+    pub fn acknowledge(&self, frames: u64) {
+        let (ref lock, ref cvar) = *self.state;
+        let mut state = lock.lock().unwrap();
+
+        state.in_flight = state.in_flight.saturating_sub(frames);
+
+        cvar.notify_all();
+    }
+
+    // Forgets the frames in flight, e.g. when the player they were written by is replaced
+    pub fn flush(&self) {
+        let (ref lock, ref cvar) = *self.state;
+        let mut state = lock.lock().unwrap();
+
+        state.in_flight = 0;
+        state.clock = None;
+
+        cvar.notify_all();
+    }
+
+    // Releases the writer of a replaced player for good, it doesn't wait anymore from then on
+    pub fn retire(&self, retired: &AtomicBool) {
+        let (ref lock, ref cvar) = *self.state;
+
+        // Under the lock, so a writer can't check the flag and then miss the wakeup
+        let _state = lock.lock().unwrap();
+
+        retired.store(true, Ordering::SeqCst);
+
+        cvar.notify_all();
+    }
+
+    // Releases a blocked writer for good, so the player thread can exit on shutdown
+    pub fn close(&self) {
+        let (ref lock, ref cvar) = *self.state;
+
+        lock.lock().unwrap().closed = true;
+
+        cvar.notify_all();
+    }
+}
+
+pub fn frames_for(duration_ms: u32, sample_rate: u64) -> u64 {
+    cmp::max(1, duration_ms as u64 * sample_rate / 1000)
+}
+
+fn frames_duration(frames: u64, sample_rate: u64) -> Duration {
+    Duration::from_secs(frames / sample_rate) + Duration::from_nanos((frames % sample_rate) * 1_000_000_000 / sample_rate)
+}
@@ -1,14 +1,15 @@
-use tokio_core::reactor::{ Core, Remote, Handle };
-
-use futures::{ Future, Stream, future };
+use futures::{ Future, Stream };
 use futures::sync::oneshot;
-use std::{ thread };
-use std::sync::{ Mutex, Arc };
+use std::{ cmp, thread };
+use std::sync::{ Mutex, Arc, Weak };
 use std::sync::mpsc;
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::time::{ Duration, Instant };
+use std::path::PathBuf;
 
 use librespot::core::authentication::Credentials;
-use librespot::core::config::{DeviceType, SessionConfig, ConnectConfig, VolumeCtrl};
-use librespot::core::session::Session;
+use librespot::core::cache::Cache;
+use librespot::core::config::{ConnectConfig, VolumeCtrl};
 
 use librespot::core::spotify_id::SpotifyId;
 use librespot::core::keymaster;
@@ -16,59 +17,331 @@ use librespot::core::keymaster::Token;
 use librespot::playback::config::PlayerConfig;
 use librespot::playback::config::Bitrate;
 use librespot::playback::audio_backend;
-use librespot::playback::player::Player;
-use librespot::playback::mixer::{Mixer, AudioFilter, MixerConfig};
-use librespot::core::cache::Cache;
-use librespot::connect::spirc::{Spirc, SpircTask};
-use librespot::connect::discovery::discovery;
-use std::path::PathBuf;
+use librespot::playback::player::{Player, PlayerEvent};
+use librespot::playback::mixer::Mixer;
+use librespot::connect::spirc::Spirc;
 use std::clone::Clone;
+use std::str::FromStr;
+
+use super::events::{Event, EventEmitter, EventSender};
+use super::session::{self, Connection};
+use super::discovery::Discovery;
+use super::mixer::{SoftwareMixer, volume_to_mixer};
+use super::pacing::{Pacer, Pacing, DECODER_SAMPLE_RATE};
+use super::audio::{AudioDelivery, AudioRing, ChunkPool};
+use super::state::{PlaybackState, PlaybackSnapshot, PlaybackStatus};
+use super::commands::{self, CommandError, CommandWaiter, Expected, PendingCommands};
+use super::queue::{PlayQueue, QueueSnapshot, RepeatMode};
+use super::replay_gain::{ ReplayGain, ReplayGainReader };
+use super::resample::Resampler;
+use super::format::OutputFormat;
+
+// Remote-control commands forwarded to the Connect device
+#[derive(Debug, Clone, Copy)]
+pub enum ConnectCommand {
+    Play,
+    Pause,
+    PlayPause,
+    Next,
+    Previous,
+    VolumeUp,
+    VolumeDown
+}
+
+impl FromStr for ConnectCommand {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "play" => Ok(ConnectCommand::Play),
+            "pause" => Ok(ConnectCommand::Pause),
+            "play-pause" => Ok(ConnectCommand::PlayPause),
+            "next" => Ok(ConnectCommand::Next),
+            "previous" => Ok(ConnectCommand::Previous),
+            "volume-up" => Ok(ConnectCommand::VolumeUp),
+            "volume-down" => Ok(ConnectCommand::VolumeDown),
+            _ => Err(())
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct PlayerOptions {
+    pub quality: Bitrate,
+    // 0-65535, shared with the Connect device once enabled
+    pub initial_volume: u16,
+    // Volume curve for `set_volume`, Connect uses the one from its own config
+    pub volume_ctrl: VolumeCtrl,
+    pub pacing: Pacing,
+    pub audio_delivery: AudioDelivery,
+    // Of the audio handed out, resampled from the decoder rate when it differs.
+    // Frame counts in these options are at this rate.
+    pub sample_rate: u32,
+    pub format: OutputFormat,
+    // In frames, no position events without it
+    pub position_interval: Option<u64>,
+    // How long a command waits for the player to confirm it
+    pub command_timeout: Duration,
+    // Scales each track to Spotify's reference loudness
+    pub normalisation: bool,
+    // In dB, added to the track gain
+    pub normalisation_pregain: f32,
+    pub gapless: bool
+}
+
+// Changes to the player config, whatever is `None` stays as it is
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlayerConfigUpdate {
+    pub normalisation: Option<bool>,
+    pub normalisation_pregain: Option<f32>,
+    pub gapless: Option<bool>
+}
+
+// How `play` loads a track
+#[derive(Debug, Clone, Copy)]
+pub struct LoadOptions {
+    pub position_ms: u32,
+    // Loads the track paused, ready for `resume`
+    pub start_playing: bool
+}
+
+impl Default for LoadOptions {
+    fn default() -> LoadOptions {
+        LoadOptions {
+            position_ms: 0,
+            start_playing: true
+        }
+    }
+}
+
+const NO_TRACK: &str = "No track is loaded";
+const CONNECT_ACTIVE: &str = "Playback is controlled through Spotify Connect";
+const UNKNOWN_DURATION: &str = "Track duration is not known yet";
+const ALREADY_PAUSED: &str = "Playback is already paused";
+const ALREADY_PLAYING: &str = "Playback is already playing";
+const TRACK_ENDED: &str = "The track has ended, play a track first";
+const TRACK_LOADING: &str = "The track is still loading";
+const END_OF_QUEUE: &str = "There is no next entry in the queue";
+const START_OF_QUEUE: &str = "There is no previous entry in the queue";
+
+// `previous` restarts the current entry instead once it has played this long
+const PREVIOUS_RESTARTS_AFTER_MS: u32 = 3000;
 
-use super::events::{Event, EventEmitter};
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
 
 pub struct SpotifyPlayer {
-    remote: Remote,
-    player: Player,
+    inner: Arc<Mutex<Inner>>,
+    sample_rate: u32,
+    format: OutputFormat,
+    event_tx: EventSender,
+    pub emitter: EventEmitter
+}
+
+// Everything tied to a single session, rebuilt by the supervisor after a reconnect
+struct Inner {
+    connection: Connection,
+    player: Arc<Mutex<Player>>,
+    // Set once the direct player is replaced, its sink stops waiting from then on
+    player_retired: Arc<AtomicBool>,
     player_config: PlayerConfig,
+    // Set by `set_player_config`, the direct player is rebuilt with the new config on its next load
+    player_config_changed: bool,
     emitted_sink: EmittedSink,
-    session: Session,
-    handle: Handle,
+    mixer: SoftwareMixer,
+    volume_ctrl: VolumeCtrl,
     spirc: Option<Spirc>,
-    task: Option<SpircTask>,
-    event_tx: mpsc::Sender<Event>,
-    pub emitter: EventEmitter
+    // Same as `player_retired`, for the sink of the player Spirc drives
+    connect_retired: Arc<AtomicBool>,
+    connect_config: Option<ConnectConfig>,
+    discovery: Option<Discovery>,
+    event_tx: EventSender,
+    direct: DirectState,
+    queue: Arc<Mutex<PlayQueue>>,
+    // Play request id of the queue entry on the direct player, `None` once `play` loaded something else
+    queue_request: Option<u64>,
+    closed: bool
 }
 
-struct EmittedSink {
-    emitter: mpsc::Sender<Event>
+// What the direct player tells the queue, about the request with the given id
+enum QueueSignal {
+    EndOfTrack(u64),
+    TimeToPreload(u64)
 }
 
-struct ImpliedMixer { }
+// Bookkeeping fed by the events of the direct player
+#[derive(Clone)]
+struct DirectState {
+    last_position: Arc<Mutex<Option<LastPosition>>>,
+    pending: Arc<Mutex<PendingCommands>>,
+    queue_tx: mpsc::Sender<QueueSignal>,
+    // Bumped whenever the direct player is replaced, events of the previous ones are dropped.
+    // Each player counts its play request ids from 0, so they would be mistaken for the new one's.
+    generation: Arc<AtomicUsize>
+}
 
-impl Mixer for ImpliedMixer {
-    fn open(_config: Option<MixerConfig>) -> ImpliedMixer {
-        ImpliedMixer {}
+impl DirectState {
+    // Register before issuing the command, so its event can't be missed
+    fn expect(&self, expected: Expected) -> CommandWaiter {
+        self.pending.lock().unwrap().register(expected)
     }
+}
 
-    fn start(&self) {}
+// Last known playback position, used to resume after a reconnect
+#[derive(Clone)]
+struct LastPosition {
+    track_id: SpotifyId,
+    position_ms: u32,
+    updated_at: Instant,
+    playing: bool,
+    // Set once the session is lost, the lost player's events no longer move it
+    frozen: bool
+}
+
+impl LastPosition {
+    fn update(last_position: &mut Option<LastPosition>, event: &PlayerEvent) {
+        if let Some(LastPosition { frozen: true, .. }) = *last_position {
+            return;
+        }
 
-    fn stop(&self) {}
+        match *event {
+            PlayerEvent::Playing { track_id, position_ms, .. } => {
+                *last_position = Some(LastPosition { track_id, position_ms, updated_at: Instant::now(), playing: true, frozen: false });
+            },
+            PlayerEvent::Paused { track_id, position_ms, .. } => {
+                *last_position = Some(LastPosition { track_id, position_ms, updated_at: Instant::now(), playing: false, frozen: false });
+            },
+            PlayerEvent::Stopped { .. } | PlayerEvent::EndOfTrack { .. } => {
+                *last_position = None;
+            },
+            _ => {}
+        }
+    }
 
-    fn volume(&self) -> u16 {
-        50
+    // Stops the clock where the session was lost, so the outage and the backoff don't count as played
+    fn freeze(last_position: &mut Option<LastPosition>) {
+        if let Some(last_position) = last_position.as_mut() {
+            last_position.position_ms = last_position.position_ms();
+            last_position.frozen = true;
+        }
     }
 
-    fn set_volume(&self, volume: u16) {
+    fn position_ms(&self) -> u32 {
+        if !self.playing || self.frozen {
+            return self.position_ms;
+        }
 
+        let elapsed = self.updated_at.elapsed();
+
+        self.position_ms + (elapsed.as_secs() as u32) * 1000 + elapsed.subsec_millis()
     }
+}
+
+// Replay gain of the track being loaded
+#[derive(Clone, Copy)]
+enum TrackGain {
+    // `announced` once its `Playing` went out without it
+    Reading { announced: bool },
+    Known(Option<ReplayGain>)
+}
 
-    fn get_audio_filter(&self) -> Option<Box<dyn AudioFilter + Send>> {
-        None
+impl TrackGain {
+    // The gain for a `Playing` event, if known. Otherwise the read sends it once done.
+    fn announce(&mut self) -> Option<ReplayGain> {
+        match *self {
+            TrackGain::Reading { .. } => {
+                *self = TrackGain::Reading { announced: true };
+
+                None
+            },
+            TrackGain::Known(gain) => gain
+        }
+    }
+}
+
+// Spirc takes ownership of the `Player` it drives, so direct playback and Connect each have one.
+// They take turns on a single output: whichever started playing last owns the sink.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Output {
+    Direct,
+    Connect
+}
+
+struct EmittedSink {
+    emitter: EventSender,
+    output: Output,
+    owner: Arc<Mutex<Output>>,
+    // Held by a write from the owner check on, so during a handover the two players never write at once
+    writer: Arc<Mutex<()>>,
+    mixer: SoftwareMixer,
+    pacer: Pacer,
+    // Set for recycled delivery
+    pool: Option<ChunkPool>,
+    // Set for ring delivery, takes precedence over the event channel
+    ring: Option<Arc<AudioRing>>,
+    // Set when the output rate differs from the decoder's. Shared by both players, so the
+    // filter runs on across handovers just like across tracks.
+    resampler: Option<Arc<Mutex<Resampler>>>,
+    format: OutputFormat,
+    sample_rate: u32,
+    // Whether the format went out ahead of the first chunk
+    format_sent: Arc<AtomicBool>,
+    // Volume is applied here before encoding, not shared between the players
+    scratch: Vec<i16>,
+    state: Arc<Mutex<PlaybackState>>,
+    position_interval: Option<u64>,
+    replay_gains: ReplayGainReader,
+    // Of the player writing to this clone, see `Pacer::retire`
+    retired: Arc<AtomicBool>
+}
+
+impl EmittedSink {
+    fn for_output(&self, output: Output, retired: Arc<AtomicBool>) -> EmittedSink {
+        EmittedSink {
+            emitter: self.emitter.clone(),
+            output,
+            owner: Arc::clone(&self.owner),
+            writer: Arc::clone(&self.writer),
+            mixer: self.mixer.clone(),
+            pacer: self.pacer.clone(),
+            pool: self.pool.clone(),
+            ring: self.ring.clone(),
+            resampler: self.resampler.clone(),
+            format: self.format,
+            sample_rate: self.sample_rate,
+            format_sent: Arc::clone(&self.format_sent),
+            scratch: Vec::new(),
+            state: Arc::clone(&self.state),
+            position_interval: self.position_interval,
+            replay_gains: self.replay_gains.clone(),
+            retired
+        }
+    }
+
+    fn emit_position(&self, frames: u64) {
+        let snapshot = self.state.lock().unwrap().snapshot(frames);
+
+        if snapshot.status != PlaybackStatus::Playing {
+            return;
+        }
+
+        if let Some(track_id) = snapshot.track_id {
+            self.emitter.send(Event::Position {
+                track_id,
+                position_ms: snapshot.position_ms,
+                duration_ms: snapshot.duration_ms
+            }).expect("event emitted");
+        }
     }
 }
 
 impl audio_backend::Sink for EmittedSink {
     fn start(&mut self) -> std::result::Result<(), std::io::Error> {
+        *self.owner.lock().unwrap() = self.output;
+
+        self.pacer.restart();
+
         Ok(())
     }
 
@@ -77,9 +350,80 @@ impl audio_backend::Sink for EmittedSink {
     }
 
     fn write(&mut self, data: &[i16]) -> std::result::Result<(), std::io::Error> {
-        self.emitter.send(Event::AudioData {
-            data: data.to_vec()
-        }).expect("event emitted");
+        let _writer = self.writer.lock().unwrap();
+
+        // Drop whatever the other player still had buffered during a handover, or once replaced
+        if self.retired.load(Ordering::SeqCst) || *self.owner.lock().unwrap() != self.output {
+            return Ok(());
+        }
+
+        let resampled = self.resampler.as_ref().map(|resampler| resampler.lock().unwrap().process(data));
+
+        let data = match resampled.as_ref() {
+            Some(resampled) => &resampled[..],
+            None => data
+        };
+
+        // The filter holds on to the first few frames it gets
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        if !self.format_sent.swap(true, Ordering::SeqCst) {
+            self.emitter.send(Event::Format {
+                format: self.format,
+                sample_rate: self.sample_rate
+            }).expect("event emitted");
+        }
+
+        let frames = (data.len() / 2) as u64;
+
+        self.pacer.wait(frames, &self.retired);
+
+        let written = self.emitter.frames();
+
+        self.scratch.clear();
+        self.scratch.extend_from_slice(data);
+        self.mixer.apply(&mut self.scratch);
+
+        let format = self.format;
+
+        if let Some(ring) = self.ring.as_ref() {
+            let emitter = &self.emitter;
+
+            ring.write(&self.scratch, &self.retired, |samples, region| format.encode(samples, region), |offset, frames| {
+                emitter.send(Event::AudioRing {
+                    offset,
+                    frames
+                }).expect("event emitted");
+
+                emitter.advance(frames as u64);
+            });
+        } else {
+            let len = frames as usize * format.bytes_per_frame();
+
+            let mut data = match self.pool.as_ref() {
+                Some(pool) => pool.take(len),
+                None => vec![0; len]
+            };
+
+            format.encode(&self.scratch, &mut data);
+
+            self.emitter.send(Event::AudioData {
+                data,
+                pool: self.pool.clone()
+            }).expect("event emitted");
+
+            self.emitter.advance(frames);
+        }
+
+        if let Some(interval) = self.position_interval {
+            let frames = self.emitter.frames();
+
+            if written / interval != frames / interval {
+                self.emit_position(frames);
+            }
+        }
 
         Ok(())
     }
@@ -87,152 +431,988 @@ impl audio_backend::Sink for EmittedSink {
 
 impl Clone for EmittedSink {
     fn clone(&self) -> EmittedSink {
-        EmittedSink {
-            emitter: self.emitter.clone()
-        }
+        self.for_output(self.output, Arc::clone(&self.retired))
     }
 }
 
-impl SpotifyPlayer {
-    pub fn new(username: String, password: String, quality: Bitrate, cache_dir: String) -> SpotifyPlayer {
-        let (session_tx, session_rx) = oneshot::channel();
-        let (remote_tx, remote_rx) = oneshot::channel();
+// Creates a player writing to `output` and forwards its events. A Connect player stops the
+// direct one (`handover`) when it starts playing, so only one of them is ever running.
+// Returns the player along with the flag that retires its sink.
+fn start_player(connection: &Connection, player_config: &PlayerConfig, emitted_sink: &EmittedSink, event_tx: &EventSender, direct: &DirectState, output: Output, handover: Option<Weak<Mutex<Player>>>) -> (Player, Arc<AtomicBool>) {
+    let retired = Arc::new(AtomicBool::new(false));
+    let cloned_sink = emitted_sink.for_output(output, Arc::clone(&retired));
 
-        let credentials = Credentials::with_password(username, password);
+    let (player, rx) = Player::new(player_config.clone(), connection.session.clone(), None, move || Box::new(cloned_sink));
 
-        let cache_config = Cache::new(PathBuf::from(cache_dir), true);
+    let cloned_event_tx = event_tx.clone();
+    let direct = direct.clone();
+    let generation = direct.generation.load(Ordering::SeqCst);
+    let owner = Arc::clone(&emitted_sink.owner);
+    let state = Arc::clone(&emitted_sink.state);
+    let session = connection.session.clone();
+    let player_config = player_config.clone();
+    let replay_gains = emitted_sink.replay_gains.clone();
+    let mixer = emitted_sink.mixer.clone();
 
-        thread::spawn(move || {
-            let mut core = Core::new().unwrap();
+    // Gain of the track being loaded, keyed by its play request id. Requested once loading starts.
+    let replay_gain: Arc<Mutex<Option<(u64, TrackGain)>>> = Arc::new(Mutex::new(None));
+
+    connection.remote.spawn(move |handle| {
+        let handle = handle.clone();
+
+        rx.for_each(move |res| {
+            debug!("PlayerEvent ({:?}): {:?}", output, res);
+
+            if output == Output::Direct && direct.generation.load(Ordering::SeqCst) != generation {
+                return Ok(());
+            }
+
+            if let PlayerEvent::Loading { play_request_id, track_id, .. } = res {
+                if !player_config.normalisation {
+                    *replay_gain.lock().unwrap() = None;
+                } else {
+                    *replay_gain.lock().unwrap() = Some((play_request_id, TrackGain::Reading { announced: false }));
+
+                    let read = replay_gains.read(&session, track_id, player_config.bitrate, player_config.normalisation_pregain);
+                    let replay_gain = Arc::clone(&replay_gain);
+                    let event_tx = cloned_event_tx.clone();
+                    let direct = direct.clone();
+
+                    // Reading takes until the player has the whole file, the events go on in the meantime
+                    handle.spawn(read.then(move |gain| {
+                        let gain = gain.unwrap_or(None);
+
+                        let mut current = replay_gain.lock().unwrap();
+
+                        if let Some((id, TrackGain::Reading { announced })) = *current {
+                            if id == play_request_id {
+                                *current = Some((id, TrackGain::Known(gain)));
+
+                                let superseded = output == Output::Direct && direct.generation.load(Ordering::SeqCst) != generation;
+
+                                if announced && !superseded {
+                                    if let Some(gain) = gain {
+                                        let _ = event_tx.send(Event::ReplayGain { play_request_id, track_id, replay_gain: gain });
+                                    }
+                                }
+                            }
+                        }
+
+                        Ok(())
+                    }));
+                }
+            }
+
+            let frames = cloned_event_tx.frames();
+
+            // The player that isn't on the output only matters once it starts playing and takes over
+            let active = match res {
+                PlayerEvent::Playing { .. } => true,
+                _ => *owner.lock().unwrap() == output
+            };
+
+            if active {
+                state.lock().unwrap().update(&res, frames);
+            }
+
+            if let PlayerEvent::VolumeSet { volume } = res {
+                mixer.set_requested_volume(volume);
+            }
+
+            match output {
+                Output::Direct => {
+                    LastPosition::update(&mut direct.last_position.lock().unwrap(), &res);
+
+                    direct.pending.lock().unwrap().handle(&res);
+
+                    let signal = match res {
+                        PlayerEvent::EndOfTrack { play_request_id, .. } => Some(QueueSignal::EndOfTrack(play_request_id)),
+                        PlayerEvent::TimeToPreloadNextTrack { play_request_id, .. } => Some(QueueSignal::TimeToPreload(play_request_id)),
+                        _ => None
+                    };
+
+                    if let Some(signal) = signal {
+                        let _ = direct.queue_tx.send(signal);
+                    }
+                },
+                Output::Connect => {
+                    if let PlayerEvent::Playing { .. } = res {
+                        if let Some(direct) = handover.as_ref().and_then(Weak::upgrade) {
+                            direct.lock().unwrap().stop();
+                        }
+                    }
+                }
+            }
+
+            // A gain that isn't known yet follows in its own event
+            let gain = match res {
+                PlayerEvent::Playing { play_request_id, .. } => match *replay_gain.lock().unwrap() {
+                    Some((id, ref mut gain)) if id == play_request_id => gain.announce(),
+                    _ => None
+                },
+                _ => None
+            };
+
+            cloned_event_tx.send_at(Event::PlayerStateChange { e: res, replay_gain: gain }, frames).expect("event was sent");
 
-            let handle = core.handle();
+            Ok(())
+        })
+    });
 
-            let session_config = SessionConfig::default();
+    (player, retired)
+}
+
+impl Inner {
+    fn start_connect(&mut self, config: ConnectConfig) {
+        // Enabling again replaces the current device, e.g. to rename it
+        if let Some(spirc) = self.spirc.take() {
+            self.retire_sink(&self.connect_retired);
+
+            spirc.shutdown();
+        }
+
+        let mixer = Box::new(self.mixer.clone());
+
+        let (player, retired) = start_player(&self.connection, &self.player_config, &self.emitted_sink, &self.event_tx, &self.direct, Output::Connect, Some(Arc::downgrade(&self.player)));
+
+        let cloned_config = config.clone();
+        let cloned_session = self.connection.session.clone();
+
+        self.connect_config = Some(config);
+        self.connect_retired = retired;
 
-            let _ = remote_tx.send(handle.remote().clone());
+        // SpircTask is not Send, so both halves are created on the session thread and the handle is sent back
+        let (spirc_tx, spirc_rx) = oneshot::channel();
 
-            let session = core.run(Session::connect(
-                session_config,
-                credentials,
-                Some(cache_config),
-                handle.clone())).unwrap();
+        self.connection.remote.spawn(move |_| {
+            let (spirc, task) = Spirc::new(cloned_config, cloned_session, player, mixer);
 
-            let _ = session_tx.send(session);
+            let _ = spirc_tx.send(spirc);
 
-            core.run(future::empty::<(), ()>()).unwrap();
+            task
         });
 
-        let core = Core::new().unwrap();
+        self.spirc = spirc_rx.wait().ok();
+    }
 
-        let handle = core.handle();
-        let remote = remote_rx.wait().unwrap();
-        let session = session_rx.wait().unwrap();
+    // State of the direct player, as long as it is on the output and has a track loaded
+    fn loaded(&self) -> Result<PlaybackSnapshot, &'static str> {
+        if *self.emitted_sink.owner.lock().unwrap() != Output::Direct {
+            return Err(CONNECT_ACTIVE);
+        }
 
-        let player_config = PlayerConfig {
-            bitrate: quality,
-            normalisation: false,
-            normalisation_pregain: 0.0,
-            gapless: true
-        };
+        let snapshot = self.emitted_sink.state.lock().unwrap().snapshot(self.event_tx.frames());
 
-        let (event_tx, event_rx) = mpsc::channel::<Event>();
+        match snapshot.track_id {
+            Some(_) => Ok(snapshot),
+            None => Err(NO_TRACK)
+        }
+    }
 
-        let emitted_sink = EmittedSink {
-            emitter: event_tx.clone()
-        };
+    // Like `loaded`, but playing or paused. The player ignores transport commands while loading and
+    // after the end of a track, without an event to settle them.
+    fn started(&self) -> Result<PlaybackSnapshot, &'static str> {
+        let snapshot = self.loaded()?;
+
+        match snapshot.status {
+            PlaybackStatus::Stopped => Err(TRACK_ENDED),
+            PlaybackStatus::Loading => Err(TRACK_LOADING),
+            _ => Ok(snapshot)
+        }
+    }
 
-        let cloned_sink = emitted_sink.clone();
+    // The direct player reports back with a `Playing` or `Paused` event
+    fn seek_to(&self, position_ms: u32) -> CommandWaiter {
+        let waiter = self.direct.expect(Expected::Position);
 
-        let (player, rx) = Player::new(player_config.clone(), session.clone(), None, move || Box::new(cloned_sink));
+        self.player.lock().unwrap().seek(position_ms);
 
-        let cloned_event_tx = event_tx.clone();
+        waiter
+    }
 
-        remote.spawn(move |_| {
-            rx.for_each(move |res| {
-                debug!("PlayerEvent: {:?}", res);
+    // Loads `track` on the direct player and registers a waiter for it.
+    // The id is only known once loading has started, so the events are held off until the waiter is in place.
+    fn load(&mut self, track: SpotifyId, options: LoadOptions) -> (u64, CommandWaiter) {
+        self.claim_output();
 
-                cloned_event_tx.send(Event::PlayerStateChange { e: res }).expect("event was sent");
+        if self.player_config_changed {
+            self.restart_direct_player();
+        }
+
+        let mut pending = self.direct.pending.lock().unwrap();
+
+        let play_request_id = self.player.lock().unwrap().load(track, options.start_playing, options.position_ms);
+
+        (play_request_id, pending.register(Expected::Load(play_request_id)))
+    }
+
+    // Makes `index` the current queue entry and loads it
+    fn load_queue_entry(&mut self, index: usize) -> CommandWaiter {
+        let track = self.queue.lock().unwrap().select(index);
+
+        info!("Queue at {}: {:?}", index, track);
+
+        let (play_request_id, waiter) = self.load(track, LoadOptions::default());
+
+        self.queue_request = Some(play_request_id);
+        self.emit_queue_changed();
+
+        waiter
+    }
+
+    // Whether the request `play_request_id` of the direct player is the queue's current entry
+    fn is_queue_request(&self, play_request_id: u64) -> bool {
+        self.queue_request == Some(play_request_id) && self.queue.lock().unwrap().snapshot().current.is_some()
+    }
+
+    // Has the direct player fetch `track_id` ahead of time. librespot doesn't say when it's done, but it puts the
+    // file in the audio cache once it has all of it. `Preloaded` follows then, along with the gain being known.
+    // A track that can't be fetched never reports it, it shows up as `Unavailable` once it's loaded.
+    fn preload(&self, track_id: SpotifyId) {
+        self.player.lock().unwrap().preload(track_id);
+
+        let fetched = self.emitted_sink.replay_gains.read(&self.connection.session, track_id, self.player_config.bitrate, self.player_config.normalisation_pregain);
+        let event_tx = self.event_tx.clone();
+
+        self.connection.remote.spawn(move |_| {
+            fetched.then(move |gain| {
+                match gain {
+                    Ok(Some(_)) => {
+                        let _ = event_tx.send(Event::Preloaded { track_id });
+                    },
+                    _ => warn!("Preloading {:?} didn't complete", track_id)
+                }
 
                 Ok(())
             })
         });
+    }
 
-        SpotifyPlayer {
-            remote: remote,
-            player: player,
+    // Replaces the direct player with one using the current config. The session stays,
+    // Connect picks the config up the next time it is enabled.
+    fn restart_direct_player(&mut self) {
+        self.player_config_changed = false;
+        self.direct.generation.fetch_add(1, Ordering::SeqCst);
+
+        // The events they wait for are dropped along with the old player
+        self.direct.pending.lock().unwrap().fail_all(CommandError::Interrupted);
+
+        self.retire_direct_player();
+
+        let (player, retired) = start_player(&self.connection, &self.player_config, &self.emitted_sink, &self.event_tx, &self.direct, Output::Direct, None);
+
+        self.player_retired = retired;
+
+        // Swapped in place, so the handle Connect uses for handovers stays valid
+        *self.player.lock().unwrap() = player;
+    }
+
+    // Stops the direct player and releases its sink for good, so it can be dropped with `self` locked.
+    // A sink left waiting on the consumer would never return, `acknowledge_audio` needs the lock too.
+    fn retire_direct_player(&self) {
+        self.player.lock().unwrap().stop();
+
+        self.retire_sink(&self.player_retired);
+    }
+
+    // Releases the sink of a player that is about to be dropped. Dropping joins its thread,
+    // which would otherwise wait on a consumer that needs the `Inner` lock to acknowledge.
+    fn retire_sink(&self, retired: &AtomicBool) {
+        self.emitted_sink.pacer.retire(retired);
+
+        if let Some(ring) = self.emitted_sink.ring.as_ref() {
+            ring.retire(retired);
+        }
+
+        // Its frames in flight don't hold up the next player
+        self.emitted_sink.pacer.flush();
+    }
+
+    fn emit_queue_changed(&self) {
+        let queue = self.queue.lock().unwrap().snapshot();
+
+        let _ = self.event_tx.send(Event::QueueChanged { queue });
+    }
+
+    // Direct playback was requested, so Connect has to let go of the output
+    fn claim_output(&self) {
+        if let Some(spirc) = self.spirc.as_ref() {
+            spirc.pause();
+        }
+    }
+
+    fn stop_connect(&mut self) {
+        self.connect_config = None;
+        self.discovery = None;
+
+        if let Some(spirc) = self.spirc.take() {
+            self.retire_sink(&self.connect_retired);
+
+            spirc.shutdown();
+        }
+    }
+
+    // Swaps in a freshly connected session. Dropping the old connection stops its `Core` thread.
+    // Returns false if the player was shut down in the meantime.
+    fn reconnect(&mut self, connection: Connection) -> bool {
+        if self.closed {
+            return false;
+        }
+
+        // Taken, so the new player's events start over from an unfrozen position
+        let resume = self.direct.last_position.lock().unwrap().take();
+
+        self.retire_direct_player();
+
+        // The old connection takes the Connect player down with its `Core` thread
+        if self.spirc.is_some() {
+            self.retire_sink(&self.connect_retired);
+        }
+
+        self.connection = connection;
+        self.player_config_changed = false;
+        self.direct.generation.fetch_add(1, Ordering::SeqCst);
+        self.direct.pending.lock().unwrap().fail_all(CommandError::Interrupted);
+
+        let (player, retired) = start_player(&self.connection, &self.player_config, &self.emitted_sink, &self.event_tx, &self.direct, Output::Direct, None);
+
+        self.player = Arc::new(Mutex::new(player));
+        self.player_retired = retired;
+
+        if let Some(config) = self.connect_config.take() {
+            self.start_connect(config);
+        }
+
+        // The new player counts its request ids from 0, the queue follows its entry over to it
+        let queue_request = self.queue_request.take();
+
+        if let Some(resume) = resume {
+            info!("Resuming {:?} at {}ms", resume.track_id, resume.position_ms());
+
+            let play_request_id = self.player.lock().unwrap().load(resume.track_id, resume.playing, resume.position_ms());
+
+            self.queue_request = queue_request.map(|_| play_request_id);
+        }
+
+        true
+    }
+
+    fn shutdown(&mut self) {
+        if self.closed {
+            return;
+        }
+
+        self.closed = true;
+        self.discovery = None;
+        self.direct.pending.lock().unwrap().fail_all(CommandError::Closed);
+        self.emitted_sink.pacer.close();
+
+        if let Some(ring) = self.emitted_sink.ring.as_ref() {
+            ring.close();
+        }
+
+        if let Some(spirc) = self.spirc.take() {
+            spirc.shutdown();
+        }
+
+        self.player.lock().unwrap().stop();
+        self.connection.shutdown();
+    }
+}
+
+// Advances the queue when the direct player reaches the end of a track, and preloads the entry after it.
+// Exits once the player internals, and with them the senders, are gone.
+fn follow_queue(inner: Weak<Mutex<Inner>>, signals: mpsc::Receiver<QueueSignal>) {
+    thread::spawn(move || {
+        for signal in signals.iter() {
+            let strong = match inner.upgrade() {
+                Some(strong) => strong,
+                None => return
+            };
+
+            let mut inner = strong.lock().unwrap();
+
+            if inner.closed {
+                return;
+            }
+
+            // Tracks loaded with `play` end without touching the queue
+            match signal {
+                QueueSignal::EndOfTrack(play_request_id) => {
+                    if !inner.is_queue_request(play_request_id) {
+                        continue;
+                    }
+
+                    let next = inner.queue.lock().unwrap().peek_advance();
+
+                    if let Some(index) = next {
+                        inner.load_queue_entry(index);
+                    }
+                },
+                QueueSignal::TimeToPreload(play_request_id) => {
+                    if !inner.is_queue_request(play_request_id) {
+                        continue;
+                    }
+
+                    let next = {
+                        let queue = inner.queue.lock().unwrap();
+
+                        queue.peek_advance().map(|index| queue.track(index))
+                    };
+
+                    if let Some(track) = next {
+                        inner.preload(track);
+                    }
+                }
+            }
+        }
+    });
+}
+
+// Watches the session from its own thread and reconnects with exponential backoff once it is lost.
+// Exits when the `SpotifyPlayer` owning `inner` is dropped.
+fn supervise(inner: Weak<Mutex<Inner>>, credentials: Credentials, cache_dir: String) {
+    // Sends through `inner` so the event channel closes as soon as the player is gone
+    let emit = |inner: &Weak<Mutex<Inner>>, event: Event| {
+        if let Some(strong) = inner.upgrade() {
+            let _ = strong.lock().unwrap().event_tx.send(event);
+        }
+    };
+
+    thread::spawn(move || loop {
+        thread::sleep(SESSION_CHECK_INTERVAL);
+
+        // Reconnect with the same config, so the Connect device keeps its id
+        let lost = match inner.upgrade() {
+            Some(strong) => {
+                let inner = strong.lock().unwrap();
+
+                if inner.connection.is_lost() {
+                    Some(inner.connection.session.config().clone())
+                } else {
+                    None
+                }
+            },
+            None => return
+        };
+
+        let session_config = match lost {
+            Some(session_config) => session_config,
+            None => continue
+        };
+
+        warn!("Session lost, reconnecting");
+
+        emit(&inner, Event::SessionLost);
+
+        if let Some(strong) = inner.upgrade() {
+            let inner = strong.lock().unwrap();
+
+            LastPosition::freeze(&mut inner.direct.last_position.lock().unwrap());
+
+            inner.direct.pending.lock().unwrap().fail_all(CommandError::SessionLost);
+        }
+
+        let mut attempt = 0;
+        let mut delay = MIN_RECONNECT_DELAY;
+
+        let connection = loop {
+            attempt += 1;
+
+            emit(&inner, Event::Reconnecting { attempt, delay });
+
+            thread::sleep(delay);
+
+            if inner.upgrade().is_none() {
+                return;
+            }
+
+            // Prefer the reusable credentials stored by the last successful login
+            let credentials = Cache::new(PathBuf::from(&cache_dir), true).credentials().unwrap_or_else(|| credentials.clone());
+
+            match session::connect(session_config.clone(), credentials, Some(Cache::new(PathBuf::from(&cache_dir), true))) {
+                Ok(connection) => break connection,
+                Err(e) => {
+                    warn!("Reconnect attempt {} failed: {}", attempt, e);
+
+                    delay = cmp::min(delay * 2, MAX_RECONNECT_DELAY);
+                }
+            }
+        };
+
+        let strong = match inner.upgrade() {
+            Some(strong) => strong,
+            None => return
+        };
+
+        if !strong.lock().unwrap().reconnect(connection) {
+            return;
+        }
+
+        info!("Session reconnected after {} attempt(s)", attempt);
+
+        emit(&inner, Event::Reconnected);
+    });
+}
+
+impl SpotifyPlayer {
+    pub fn new(connection: Connection, credentials: Credentials, cache_dir: String, options: PlayerOptions) -> SpotifyPlayer {
+        let player_config = PlayerConfig {
+            bitrate: options.quality,
+            normalisation: options.normalisation,
+            normalisation_pregain: options.normalisation_pregain,
+            gapless: options.gapless
+        };
+
+        let (event_tx, emitter) = EventSender::channel();
+
+        let mixer = SoftwareMixer::new(options.initial_volume, &options.volume_ctrl);
+
+        let emitted_sink = EmittedSink {
+            emitter: event_tx.clone(),
+            output: Output::Direct,
+            owner: Arc::new(Mutex::new(Output::Direct)),
+            writer: Arc::new(Mutex::new(())),
+            mixer: mixer.clone(),
+            pacer: Pacer::new(options.pacing, options.sample_rate as u64),
+            pool: match options.audio_delivery {
+                AudioDelivery::Recycled => Some(ChunkPool::new()),
+                _ => None
+            },
+            ring: match options.audio_delivery {
+                AudioDelivery::Ring { frames } => Some(Arc::new(AudioRing::new(frames, options.format.bytes_per_frame()))),
+                _ => None
+            },
+            resampler: if options.sample_rate as u64 == DECODER_SAMPLE_RATE {
+                None
+            } else {
+                Some(Arc::new(Mutex::new(Resampler::new(options.sample_rate))))
+            },
+            format: options.format,
+            sample_rate: options.sample_rate,
+            format_sent: Arc::new(AtomicBool::new(false)),
+            scratch: Vec::new(),
+            state: Arc::new(Mutex::new(PlaybackState::new(options.sample_rate as u64))),
+            position_interval: options.position_interval,
+            replay_gains: ReplayGainReader::new(),
+            retired: Arc::new(AtomicBool::new(false))
+        };
+
+        let (queue_tx, queue_rx) = mpsc::channel();
+
+        let direct = DirectState {
+            last_position: Arc::new(Mutex::new(None)),
+            pending: Arc::new(Mutex::new(PendingCommands::new(options.command_timeout))),
+            queue_tx,
+            generation: Arc::new(AtomicUsize::new(0))
+        };
+
+        commands::expire_overdue(&direct.pending);
+
+        let (player, player_retired) = start_player(&connection, &player_config, &emitted_sink, &event_tx, &direct, Output::Direct, None);
+
+        let inner = Arc::new(Mutex::new(Inner {
+            connection,
+            player: Arc::new(Mutex::new(player)),
+            player_retired,
             player_config,
+            player_config_changed: false,
             emitted_sink,
-            event_tx,
-            session: session,
-            handle: handle,
+            mixer,
+            volume_ctrl: options.volume_ctrl,
             spirc: None,
-            task: None,
-            emitter: EventEmitter {
-                events: Arc::new(Mutex::new(event_rx))
+            connect_retired: Arc::new(AtomicBool::new(false)),
+            connect_config: None,
+            discovery: None,
+            event_tx: event_tx.clone(),
+            direct,
+            queue: Arc::new(Mutex::new(PlayQueue::new())),
+            queue_request: None,
+            closed: false
+        }));
+
+        supervise(Arc::downgrade(&inner), credentials, cache_dir);
+        follow_queue(Arc::downgrade(&inner), queue_rx);
+
+        SpotifyPlayer {
+            inner,
+            sample_rate: options.sample_rate,
+            format: options.format,
+            event_tx,
+            emitter
+        }
+    }
+
+    // Stops Connect, the player and the session, and ends the session thread.
+    // The event channel closes once the last reference to the player internals is gone.
+    pub fn shutdown(&mut self) {
+        self.inner.lock().unwrap().shutdown();
+    }
+
+    pub fn emit(&self, event: Event) {
+        self.event_tx.send(event).expect("event was sent");
+    }
+
+    // Returns the id librespot tags the events of this load with, and a waiter
+    // for it to start playing, or to be ready when loaded paused
+    pub fn play(&mut self, track_id: SpotifyId, options: LoadOptions) -> (u64, CommandWaiter) {
+        info!("Track: {:?} {:?}", track_id, options);
+
+        let mut inner = self.inner.lock().unwrap();
+
+        let (play_request_id, waiter) = inner.load(track_id, options);
+
+        inner.queue_request = None;
+
+        (play_request_id, waiter)
+    }
+
+    // Takes effect with the next track the direct player loads, without touching the session
+    pub fn set_player_config(&self, update: PlayerConfigUpdate) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some(normalisation) = update.normalisation {
+            inner.player_config.normalisation = normalisation;
+        }
+
+        if let Some(pregain) = update.normalisation_pregain {
+            inner.player_config.normalisation_pregain = pregain;
+        }
+
+        if let Some(gapless) = update.gapless {
+            inner.player_config.gapless = gapless;
+        }
+
+        inner.player_config_changed = true;
+    }
+
+    // The consumer has read `frames` frames of audio data, only meaningful with consumer pacing
+    pub fn acknowledge_audio(&self, frames: u64) {
+        let inner = self.inner.lock().unwrap();
+
+        inner.emitted_sink.pacer.acknowledge(frames);
+
+        if let Some(ring) = inner.emitted_sink.ring.as_ref() {
+            ring.release(frames);
+        }
+    }
+
+    pub fn get_state(&self) -> PlaybackSnapshot {
+        let inner = self.inner.lock().unwrap();
+
+        let frames = inner.event_tx.frames();
+        let snapshot = inner.emitted_sink.state.lock().unwrap().snapshot(frames);
+
+        snapshot
+    }
+
+    // Of the audio data, in Hz
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn format(&self) -> OutputFormat {
+        self.format
+    }
+
+    // Present with ring delivery, JS has to attach the memory before any audio is written
+    pub fn audio_ring(&self) -> Option<Arc<AudioRing>> {
+        self.inner.lock().unwrap().emitted_sink.ring.clone()
+    }
+
+    pub fn enable_connect(&mut self, config: ConnectConfig) {
+        self.inner.lock().unwrap().start_connect(config);
+    }
+
+    // Keeps zeroconf discovery running alongside the session. A later login from the LAN
+    // switches the session, and with it the Connect device, over to that user.
+    pub fn follow_discovery(&mut self, discovery: Discovery, credentials_rx: mpsc::Receiver<Credentials>, cache_dir: String) {
+        let inner = Arc::downgrade(&self.inner);
+
+        self.inner.lock().unwrap().discovery = Some(discovery);
+
+        thread::spawn(move || {
+            for credentials in credentials_rx.iter() {
+                let strong = match inner.upgrade() {
+                    Some(strong) => strong,
+                    None => return
+                };
+
+                let session_config = {
+                    let inner = strong.lock().unwrap();
+
+                    let _ = inner.event_tx.send(Event::UserConnected { username: credentials.username.clone() });
+
+                    inner.connection.session.config().clone()
+                };
+
+                match session::connect(session_config, credentials, Some(Cache::new(PathBuf::from(&cache_dir), true))) {
+                    Ok(connection) => {
+                        strong.lock().unwrap().reconnect(connection);
+                    },
+                    Err(e) => warn!("Discovery login failed: {}", e)
+                }
             }
+        });
+    }
+
+    pub fn disable_connect(&mut self) {
+        self.inner.lock().unwrap().stop_connect();
+    }
+
+    pub fn connect_command(&self, command: ConnectCommand) -> Result<(), &'static str> {
+        let inner = self.inner.lock().unwrap();
+
+        let spirc = inner.spirc.as_ref().ok_or("Spotify Connect is not enabled")?;
+
+        match command {
+            ConnectCommand::Play => spirc.play(),
+            ConnectCommand::Pause => spirc.pause(),
+            ConnectCommand::PlayPause => spirc.play_pause(),
+            ConnectCommand::Next => spirc.next(),
+            ConnectCommand::Previous => spirc.prev(),
+            ConnectCommand::VolumeUp => spirc.volume_up(),
+            ConnectCommand::VolumeDown => spirc.volume_down()
+        };
+
+        Ok(())
+    }
+
+    pub fn set_volume(&self, volume: u16) {
+        let inner = self.inner.lock().unwrap();
+
+        inner.mixer.set_volume(volume_to_mixer(volume, &inner.volume_ctrl));
+        inner.mixer.set_requested_volume(volume);
+        inner.player.lock().unwrap().emit_volume_set_event(volume);
+    }
+
+    // As last set here or through Connect, on the same 0-65535 scale `VolumeSet` events report
+    pub fn get_volume(&self) -> u16 {
+        self.inner.lock().unwrap().mixer.requested_volume()
+    }
+
+    // Transport commands return a waiter for the player event that confirms them
+
+    pub fn stop(&self) -> Result<CommandWaiter, &'static str> {
+        let inner = self.inner.lock().unwrap();
+
+        inner.loaded()?;
+
+        let waiter = inner.direct.expect(Expected::Stopped);
+
+        inner.player.lock().unwrap().stop();
+
+        Ok(waiter)
+    }
+
+    pub fn pause(&self) -> Result<CommandWaiter, &'static str> {
+        let inner = self.inner.lock().unwrap();
+
+        if inner.started()?.status == PlaybackStatus::Paused {
+            return Err(ALREADY_PAUSED);
         }
+
+        let waiter = inner.direct.expect(Expected::Paused);
+
+        inner.player.lock().unwrap().pause();
+
+        Ok(waiter)
     }
 
-    pub fn play(&mut self, track_id: String) {
-        let track = SpotifyId::from_base62(&track_id).unwrap();
+    pub fn resume(&self) -> Result<CommandWaiter, &'static str> {
+        let inner = self.inner.lock().unwrap();
 
-        info!("Track: {:?}", track);
+        if inner.started()?.status == PlaybackStatus::Playing {
+            return Err(ALREADY_PLAYING);
+        }
+
+        let waiter = inner.direct.expect(Expected::Playing);
 
-        self.player.load(track, true, 0);
+        inner.player.lock().unwrap().play();
+
+        Ok(waiter)
     }
 
-    pub fn enable_connect(&mut self, device_name: String, device_type: DeviceType, initial_volume: u16, volume_ctrl: VolumeCtrl) {
-        let config = ConnectConfig {
-            name: device_name,
-            device_type,
-            volume: initial_volume,
-            autoplay: true,
-            volume_ctrl
+    // Pauses while playing, resumes otherwise
+    pub fn toggle(&self) -> Result<CommandWaiter, &'static str> {
+        let inner = self.inner.lock().unwrap();
+
+        let snapshot = inner.started()?;
+        let mut player = inner.player.lock().unwrap();
+
+        let waiter = match snapshot.status {
+            PlaybackStatus::Playing => {
+                let waiter = inner.direct.expect(Expected::Paused);
+
+                player.pause();
+
+                waiter
+            },
+            _ => {
+                let waiter = inner.direct.expect(Expected::Playing);
+
+                player.play();
+
+                waiter
+            }
         };
 
-        let mixer = Box::new(ImpliedMixer {});
+        Ok(waiter)
+    }
 
-        let cloned_sink = self.emitted_sink.clone();
+    pub fn seek(&self, position_ms: u32) -> Result<CommandWaiter, &'static str> {
+        let inner = self.inner.lock().unwrap();
 
-        let (player, _) = Player::new(self.player_config.clone(), self.session.clone(), None, move || Box::new(cloned_sink));
+        inner.started()?;
 
-        let cloned_config = config.clone();
-        let cloned_session = self.session.clone();
-        
-        // let (spirc, task) = Spirc::new(cloned_config, cloned_session, player, mixer);
+        Ok(inner.seek_to(position_ms))
+    }
 
-        // self.spirc = Some(spirc);
+    // Seeks `delta_ms` from the current position, clamped to the track
+    pub fn seek_by(&self, delta_ms: i64) -> Result<CommandWaiter, &'static str> {
+        let inner = self.inner.lock().unwrap();
 
-        // self.task = Some(task);
+        let snapshot = inner.started()?;
 
-        self.remote.spawn(move |_| {
-            let (spirc, task) = Spirc::new(cloned_config, cloned_session, player, mixer);
+        let mut position_ms = cmp::max(0, snapshot.position_ms as i64 + delta_ms);
 
-            task
-        });
+        if snapshot.duration_ms > 0 {
+            position_ms = cmp::min(position_ms, snapshot.duration_ms as i64);
+        }
+
+        Ok(inner.seek_to(position_ms as u32))
+    }
 
-        // discovery(&self.handle, config.clone(), self.session.device_id().clone().to_string(), 0).expect("started discovery process");
+    // `fraction` of the duration, between 0 and 1
+    pub fn seek_fraction(&self, fraction: f64) -> Result<CommandWaiter, &'static str> {
+        let inner = self.inner.lock().unwrap();
+
+        let snapshot = inner.started()?;
+
+        if snapshot.duration_ms == 0 {
+            return Err(UNKNOWN_DURATION);
+        }
+
+        Ok(inner.seek_to((snapshot.duration_ms as f64 * fraction) as u32))
+    }
+
+    // Back to the start of the track, playing
+    pub fn restart(&self) -> Result<CommandWaiter, &'static str> {
+        let inner = self.inner.lock().unwrap();
+
+        inner.started()?;
+
+        let waiter = inner.direct.expect(Expected::Playing);
+
+        let mut player = inner.player.lock().unwrap();
+
+        player.seek(0);
+        player.play();
+
+        Ok(waiter)
+    }
+
+    pub fn preload(&self, track_id: SpotifyId) {
+        self.inner.lock().unwrap().preload(track_id);
+    }
+
+    pub fn enqueue(&self, tracks: Vec<SpotifyId>) {
+        let inner = self.inner.lock().unwrap();
+
+        inner.queue.lock().unwrap().enqueue(tracks);
+        inner.emit_queue_changed();
+    }
+
+    pub fn insert_next(&self, tracks: Vec<SpotifyId>) {
+        let inner = self.inner.lock().unwrap();
+
+        inner.queue.lock().unwrap().insert_next(tracks);
+        inner.emit_queue_changed();
+    }
+
+    pub fn remove_from_queue(&self, index: usize) -> Result<(), &'static str> {
+        let inner = self.inner.lock().unwrap();
+
+        inner.queue.lock().unwrap().remove(index)?;
+        inner.emit_queue_changed();
+
+        Ok(())
+    }
+
+    pub fn move_in_queue(&self, from: usize, to: usize) -> Result<(), &'static str> {
+        let inner = self.inner.lock().unwrap();
+
+        inner.queue.lock().unwrap().move_entry(from, to)?;
+        inner.emit_queue_changed();
+
+        Ok(())
+    }
+
+    // Whatever is playing keeps playing
+    pub fn clear_queue(&self) {
+        let inner = self.inner.lock().unwrap();
+
+        inner.queue.lock().unwrap().clear();
+        inner.emit_queue_changed();
+    }
+
+    pub fn set_shuffle(&self, shuffle: bool) {
+        let inner = self.inner.lock().unwrap();
+
+        inner.queue.lock().unwrap().set_shuffle(shuffle);
+        inner.emit_queue_changed();
     }
 
-    pub fn stop(&self) {
-        self.player.stop();
+    pub fn set_repeat(&self, repeat: RepeatMode) {
+        let inner = self.inner.lock().unwrap();
+
+        inner.queue.lock().unwrap().set_repeat(repeat);
+        inner.emit_queue_changed();
     }
 
-    pub fn pause(&self) {
-        self.player.pause();
+    pub fn get_queue(&self) -> QueueSnapshot {
+        let inner = self.inner.lock().unwrap();
+
+        let snapshot = inner.queue.lock().unwrap().snapshot();
+
+        snapshot
     }
 
-    pub fn seek(&self, position_ms: u32) {
-        self.player.seek(position_ms);
+    pub fn next(&self) -> Result<CommandWaiter, &'static str> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let index = inner.queue.lock().unwrap().peek_next().ok_or(END_OF_QUEUE)?;
+
+        Ok(inner.load_queue_entry(index))
+    }
+
+    // Goes back an entry, or to the start of the current one once it has been playing for a bit
+    pub fn previous(&self) -> Result<CommandWaiter, &'static str> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Ok(snapshot) = inner.started() {
+            if snapshot.position_ms > PREVIOUS_RESTARTS_AFTER_MS {
+                return Ok(inner.seek_to(0));
+            }
+        }
+
+        let index = inner.queue.lock().unwrap().peek_previous().ok_or(START_OF_QUEUE)?;
+
+        Ok(inner.load_queue_entry(index))
     }
 
     pub fn get_token<F>(&self, client_id: String, scopes: String, cb: F)
         where F: FnOnce(Option<Token>) {
 
-        let local_session = self.session.clone();
+        let (local_session, remote) = {
+            let inner = self.inner.lock().unwrap();
+
+            (inner.connection.session.clone(), inner.connection.remote.clone())
+        };
+
         let (token_tx, token_rx) = oneshot::channel();
 
-        self.remote.spawn(move |_| {
+        remote.spawn(move |_| {
             keymaster::get_token(&local_session, &client_id, &scopes).then(move |res| {
                 let _ = token_tx.send(res);
                 Ok(())
@@ -250,3 +1430,11 @@ impl SpotifyPlayer {
         };
     }
 }
+
+// Reached through `shutdown` on the JS side. Garbage collection is no substitute: neon has no weak
+// persistent handles, so an instance that listens for events, as every wrapper does, is never collected.
+impl Drop for SpotifyPlayer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
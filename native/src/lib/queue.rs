@@ -0,0 +1,415 @@
+use std::str::FromStr;
+
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+
+use librespot::core::spotify_id::SpotifyId;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RepeatMode {
+    Off,
+    One,
+    All
+}
+
+impl RepeatMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RepeatMode::Off => "off",
+            RepeatMode::One => "one",
+            RepeatMode::All => "all"
+        }
+    }
+}
+
+impl FromStr for RepeatMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(RepeatMode::Off),
+            "one" => Ok(RepeatMode::One),
+            "all" => Ok(RepeatMode::All),
+            _ => Err(())
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QueueSnapshot {
+    pub tracks: Vec<SpotifyId>,
+    pub current: Option<usize>,
+    pub shuffle: bool,
+    pub repeat: RepeatMode
+}
+
+// An entry of the queue. The key tells apart entries of the same track.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    key: u64,
+    track: SpotifyId
+}
+
+// Tracks played by the direct player, in order. `current` is the entry that is playing,
+// tracks played with `play` leave it alone, so the queue picks up where it was afterwards.
+pub struct PlayQueue {
+    // In play order, shuffled or not
+    entries: Vec<Entry>,
+    // Keys in the order the entries were queued, which turning shuffle off goes back to
+    queued: Vec<u64>,
+    next_key: u64,
+    current: Option<usize>,
+    shuffle: bool,
+    repeat: RepeatMode
+}
+
+const OUT_OF_RANGE: &str = "Queue index is out of range";
+const REMOVE_CURRENT: &str = "The playing entry can't be removed, skip it first";
+
+impl PlayQueue {
+    pub fn new() -> PlayQueue {
+        PlayQueue {
+            entries: Vec::new(),
+            queued: Vec::new(),
+            next_key: 0,
+            current: None,
+            shuffle: false,
+            repeat: RepeatMode::Off
+        }
+    }
+
+    pub fn enqueue(&mut self, tracks: Vec<SpotifyId>) {
+        let start = self.entries.len();
+        let entries = self.entries_of(tracks);
+
+        self.queued.extend(entries.iter().map(|entry| entry.key));
+        self.entries.extend(entries);
+        self.shuffle_from(start);
+    }
+
+    // Right after the current entry, or at the front when nothing from the queue is playing.
+    // Also in the queued order, so they stay next when shuffle is turned off.
+    pub fn insert_next(&mut self, tracks: Vec<SpotifyId>) {
+        let at = self.current.map_or(0, |current| current + 1);
+        let entries = self.entries_of(tracks);
+
+        let queued_at = match self.current {
+            Some(current) => self.queued_position(self.entries[current].key) + 1,
+            None => 0
+        };
+
+        self.queued.splice(queued_at..queued_at, entries.iter().map(|entry| entry.key));
+        self.entries.splice(at..at, entries);
+    }
+
+    pub fn remove(&mut self, index: usize) -> Result<(), &'static str> {
+        if index >= self.entries.len() {
+            return Err(OUT_OF_RANGE);
+        }
+
+        match self.current {
+            Some(current) if current == index => return Err(REMOVE_CURRENT),
+            Some(current) if current > index => self.current = Some(current - 1),
+            _ => {}
+        }
+
+        let entry = self.entries.remove(index);
+        let position = self.queued_position(entry.key);
+
+        self.queued.remove(position);
+
+        Ok(())
+    }
+
+    // Moves within the play order. Unshuffled that is also the queued order.
+    pub fn move_entry(&mut self, from: usize, to: usize) -> Result<(), &'static str> {
+        if from >= self.entries.len() || to >= self.entries.len() {
+            return Err(OUT_OF_RANGE);
+        }
+
+        let entry = self.entries.remove(from);
+
+        self.entries.insert(to, entry);
+
+        if !self.shuffle {
+            self.queued = self.entries.iter().map(|entry| entry.key).collect();
+        }
+
+        // The current entry moves with itself, or shifts by one when an entry is moved across it
+        self.current = self.current.map(|current| {
+            if current == from {
+                to
+            } else if from < current && to >= current {
+                current - 1
+            } else if from > current && to <= current {
+                current + 1
+            } else {
+                current
+            }
+        });
+
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.queued.clear();
+        self.current = None;
+    }
+
+    // Shuffling reorders the entries that are still to come. Turning it off goes back to the order
+    // they were queued in, the current entry stays current.
+    pub fn set_shuffle(&mut self, shuffle: bool) {
+        if self.shuffle && !shuffle {
+            self.restore_queued_order();
+        }
+
+        self.shuffle = shuffle;
+
+        let start = self.current.map_or(0, |current| current + 1);
+
+        self.shuffle_from(start);
+    }
+
+    pub fn set_repeat(&mut self, repeat: RepeatMode) {
+        self.repeat = repeat;
+    }
+
+    // The entry played once the current one ends. Repeat-one only applies here, not to skipping.
+    pub fn peek_advance(&self) -> Option<usize> {
+        match (self.repeat, self.current) {
+            (RepeatMode::One, Some(current)) => Some(current),
+            _ => self.peek_next()
+        }
+    }
+
+    pub fn peek_next(&self) -> Option<usize> {
+        if self.entries.is_empty() {
+            return None;
+        }
+
+        let next = self.current.map_or(0, |current| current + 1);
+
+        if next < self.entries.len() {
+            Some(next)
+        } else if self.repeat == RepeatMode::All {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    pub fn peek_previous(&self) -> Option<usize> {
+        match self.current {
+            Some(current) if current > 0 => Some(current - 1),
+            Some(_) if self.repeat == RepeatMode::All => Some(self.entries.len() - 1),
+            _ => None
+        }
+    }
+
+    // Makes `index` the current entry and returns its track
+    pub fn select(&mut self, index: usize) -> SpotifyId {
+        self.current = Some(index);
+
+        self.entries[index].track
+    }
+
+    pub fn track(&self, index: usize) -> SpotifyId {
+        self.entries[index].track
+    }
+
+    pub fn snapshot(&self) -> QueueSnapshot {
+        QueueSnapshot {
+            tracks: self.entries.iter().map(|entry| entry.track).collect(),
+            current: self.current,
+            shuffle: self.shuffle,
+            repeat: self.repeat
+        }
+    }
+
+    fn entries_of(&mut self, tracks: Vec<SpotifyId>) -> Vec<Entry> {
+        tracks.into_iter().map(|track| {
+            let key = self.next_key;
+
+            self.next_key += 1;
+
+            Entry { key, track }
+        }).collect()
+    }
+
+    fn queued_position(&self, key: u64) -> usize {
+        self.queued.iter().position(|queued| *queued == key).expect("every entry is queued")
+    }
+
+    fn restore_queued_order(&mut self) {
+        let current = self.current.map(|current| self.entries[current].key);
+
+        let mut entries = Vec::with_capacity(self.entries.len());
+
+        for key in self.queued.iter() {
+            let entry = self.entries.iter().find(|entry| entry.key == *key).expect("every queued key has an entry");
+
+            entries.push(*entry);
+        }
+
+        self.entries = entries;
+        self.current = current.map(|key| self.entries.iter().position(|entry| entry.key == key).expect("current entry is kept"));
+    }
+
+    fn shuffle_from(&mut self, start: usize) {
+        if self.shuffle && start < self.entries.len() {
+            self.entries[start..].shuffle(&mut thread_rng());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(n: u8) -> SpotifyId {
+        SpotifyId::from_base16(&format!("{:032x}", n)).unwrap()
+    }
+
+    fn queue_of(count: u8) -> PlayQueue {
+        let mut queue = PlayQueue::new();
+
+        queue.enqueue((0..count).map(track).collect());
+
+        queue
+    }
+
+    #[test]
+    fn empty_queue_has_nowhere_to_go() {
+        let mut queue = PlayQueue::new();
+
+        assert_eq!(queue.peek_next(), None);
+        assert_eq!(queue.peek_previous(), None);
+        assert_eq!(queue.peek_advance(), None);
+
+        queue.set_repeat(RepeatMode::All);
+
+        assert_eq!(queue.peek_next(), None);
+        assert_eq!(queue.peek_advance(), None);
+        assert_eq!(queue.remove(0), Err(OUT_OF_RANGE));
+        assert_eq!(queue.move_entry(0, 0), Err(OUT_OF_RANGE));
+    }
+
+    #[test]
+    fn starts_at_the_first_entry() {
+        let mut queue = queue_of(3);
+
+        assert_eq!(queue.peek_next(), Some(0));
+        assert_eq!(queue.select(0), track(0));
+        assert_eq!(queue.peek_next(), Some(1));
+        assert_eq!(queue.peek_previous(), None);
+    }
+
+    #[test]
+    fn removing_before_current_keeps_it_playing() {
+        let mut queue = queue_of(4);
+
+        queue.select(2);
+
+        assert_eq!(queue.remove(0), Ok(()));
+        assert_eq!(queue.snapshot().current, Some(1));
+        assert_eq!(queue.track(1), track(2));
+        assert_eq!(queue.remove(1), Err(REMOVE_CURRENT));
+        assert_eq!(queue.remove(2), Ok(()));
+        assert_eq!(queue.snapshot().current, Some(1));
+    }
+
+    #[test]
+    fn moving_across_current_shifts_it() {
+        let mut queue = queue_of(4);
+
+        queue.select(2);
+
+        // From before to after the current entry
+        queue.move_entry(0, 3).unwrap();
+        assert_eq!(queue.snapshot().current, Some(1));
+        assert_eq!(queue.track(1), track(2));
+
+        // From after to before it
+        queue.move_entry(3, 0).unwrap();
+        assert_eq!(queue.snapshot().current, Some(2));
+        assert_eq!(queue.track(2), track(2));
+
+        // The current entry itself
+        queue.move_entry(2, 0).unwrap();
+        assert_eq!(queue.snapshot().current, Some(0));
+        assert_eq!(queue.track(0), track(2));
+    }
+
+    #[test]
+    fn repeat_all_wraps_both_ways() {
+        let mut queue = queue_of(3);
+
+        queue.select(2);
+
+        assert_eq!(queue.peek_next(), None);
+
+        queue.set_repeat(RepeatMode::All);
+
+        assert_eq!(queue.peek_next(), Some(0));
+        assert_eq!(queue.peek_advance(), Some(0));
+
+        queue.select(0);
+
+        assert_eq!(queue.peek_previous(), Some(2));
+    }
+
+    #[test]
+    fn repeat_one_only_applies_to_advancing() {
+        let mut queue = queue_of(3);
+
+        queue.select(1);
+        queue.set_repeat(RepeatMode::One);
+
+        assert_eq!(queue.peek_advance(), Some(1));
+        assert_eq!(queue.peek_next(), Some(2));
+    }
+
+    #[test]
+    fn inserts_after_current() {
+        let mut queue = queue_of(3);
+
+        queue.insert_next(vec![track(10)]);
+        assert_eq!(queue.track(0), track(10));
+
+        queue.select(1);
+        queue.insert_next(vec![track(11)]);
+        assert_eq!(queue.track(2), track(11));
+        assert_eq!(queue.peek_next(), Some(2));
+    }
+
+    #[test]
+    fn unshuffling_restores_the_queued_order() {
+        let mut queue = queue_of(8);
+
+        queue.select(2);
+        queue.set_shuffle(true);
+        queue.insert_next(vec![track(10)]);
+
+        let playing = queue.select(5);
+
+        queue.set_shuffle(false);
+
+        let expected: Vec<SpotifyId> = vec![0, 1, 2, 10, 3, 4, 5, 6, 7].into_iter().map(track).collect();
+        let snapshot = queue.snapshot();
+
+        assert_eq!(snapshot.tracks, expected);
+        assert_eq!(queue.track(snapshot.current.unwrap()), playing);
+    }
+
+    #[test]
+    fn clearing_forgets_current() {
+        let mut queue = queue_of(3);
+
+        queue.select(1);
+        queue.clear();
+
+        assert_eq!(queue.snapshot().current, None);
+        assert_eq!(queue.peek_advance(), None);
+    }
+}
@@ -0,0 +1,218 @@
+use futures::Future;
+use futures::sync::oneshot;
+use std::collections::HashMap;
+use std::io::{ Read, Seek, SeekFrom };
+use std::sync::mpsc::{ self, RecvTimeoutError };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use librespot::audio::AudioDecrypt;
+use librespot::core::audio_key::AudioKey;
+use librespot::core::session::Session;
+use librespot::core::spotify_id::{ FileId, SpotifyId };
+use librespot::metadata::{ AudioItem, FileFormat };
+use librespot::playback::config::Bitrate;
+
+// Where the gain values sit in the decrypted header of Spotify's Vorbis files
+const NORMALISATION_HEADER_OFFSET: u64 = 144;
+// Tracks whose values are kept, so playing one again doesn't read it again
+const MAX_CACHED_TRACKS: usize = 512;
+// How often files the player is still downloading are looked for in the cache
+const CACHE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+// After this long the file is taken to never arrive, e.g. when the track was skipped early
+const MAX_DOWNLOAD_WAIT: Duration = Duration::from_secs(120);
+
+// The gain librespot applies to a track with normalisation on
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayGain {
+    pub track_gain_db: f32,
+    pub track_peak: f32,
+    pub pregain_db: f32,
+    // Linear factor the samples are scaled by
+    pub factor: f32
+}
+
+impl ReplayGain {
+    // Same as the player: the track gain plus pregain, lowered so the peak doesn't clip
+    fn new(track_gain_db: f32, track_peak: f32, pregain_db: f32) -> ReplayGain {
+        let mut factor = 10f32.powf((track_gain_db + pregain_db) / 20.0);
+
+        if track_peak > 0.0 && factor * track_peak > 1.0 {
+            factor = 1.0 / track_peak;
+        }
+
+        ReplayGain {
+            track_gain_db,
+            track_peak,
+            pregain_db,
+            factor
+        }
+    }
+}
+
+struct Request {
+    session: Session,
+    track_id: SpotifyId,
+    bitrate: Bitrate,
+    pregain_db: f32,
+    done: oneshot::Sender<Option<ReplayGain>>
+}
+
+impl Request {
+    fn finish(self, values: Option<(f32, f32)>) {
+        let pregain_db = self.pregain_db;
+
+        let _ = self.done.send(values.map(|(track_gain_db, track_peak)| ReplayGain::new(track_gain_db, track_peak, pregain_db)));
+    }
+}
+
+// A request whose file the player is still downloading
+struct Waiting {
+    request: Request,
+    file_id: FileId,
+    key: AudioKey,
+    since: Instant
+}
+
+// Reads gains on its own thread, reading blocks on the session. The values come from the copy of the file
+// librespot puts in the audio cache once it has downloaded all of it, so no file is fetched a second time.
+// The thread exits once every handle is gone.
+#[derive(Clone)]
+pub struct ReplayGainReader {
+    requests: mpsc::Sender<Request>
+}
+
+impl ReplayGainReader {
+    pub fn new() -> ReplayGainReader {
+        let (requests, rx) = mpsc::channel::<Request>();
+
+        thread::spawn(move || {
+            // Track gain and peak, the pregain may change in between
+            let mut cache: HashMap<SpotifyId, (f32, f32)> = HashMap::new();
+            let mut waiting: Vec<Waiting> = Vec::new();
+
+            loop {
+                match rx.recv_timeout(CACHE_POLL_INTERVAL) {
+                    Ok(request) => {
+                        if let Some(values) = cache.get(&request.track_id) {
+                            let values = *values;
+
+                            request.finish(Some(values));
+                        } else {
+                            match locate_file(&request.session, request.track_id, request.bitrate) {
+                                Ok((file_id, key)) => waiting.push(Waiting { request, file_id, key, since: Instant::now() }),
+                                Err(e) => {
+                                    warn!("Reading the replay gain of {:?} failed: {}", request.track_id, e);
+
+                                    request.finish(None);
+                                }
+                            }
+                        }
+                    },
+                    Err(RecvTimeoutError::Timeout) => {},
+                    Err(RecvTimeoutError::Disconnected) => return
+                }
+
+                let mut still_waiting = Vec::with_capacity(waiting.len());
+
+                for entry in waiting.drain(..) {
+                    match read_cached(&entry.request.session, entry.file_id, entry.key) {
+                        Ok(Some(values)) => {
+                            if cache.len() >= MAX_CACHED_TRACKS {
+                                cache.clear();
+                            }
+
+                            cache.insert(entry.request.track_id, values);
+
+                            entry.request.finish(Some(values));
+                        },
+                        Ok(None) if entry.since.elapsed() < MAX_DOWNLOAD_WAIT => still_waiting.push(entry),
+                        Ok(None) => {
+                            warn!("Gave up on the replay gain of {:?}, its file never reached the cache", entry.request.track_id);
+
+                            entry.request.finish(None);
+                        },
+                        Err(e) => {
+                            warn!("Reading the replay gain of {:?} failed: {}", entry.request.track_id, e);
+
+                            entry.request.finish(None);
+                        }
+                    }
+                }
+
+                waiting = still_waiting;
+            }
+        });
+
+        ReplayGainReader { requests }
+    }
+
+    // Resolves with the gain of `track_id` once the player has downloaded all of it, or None when it couldn't be read.
+    // Values read once are answered right away from then on.
+    pub fn read(&self, session: &Session, track_id: SpotifyId, bitrate: Bitrate, pregain_db: f32) -> oneshot::Receiver<Option<ReplayGain>> {
+        let (done, gain) = oneshot::channel();
+
+        let _ = self.requests.send(Request {
+            session: session.clone(),
+            track_id,
+            bitrate,
+            pregain_db,
+            done
+        });
+
+        gain
+    }
+}
+
+// The file of `track_id` the player picks, and its key. Only metadata and the key are fetched here, both small.
+fn locate_file(session: &Session, track_id: SpotifyId, bitrate: Bitrate) -> Result<(FileId, AudioKey), String> {
+    if session.cache().is_none() {
+        return Err("No audio cache to read the file from".to_string());
+    }
+
+    let item = AudioItem::get_audio_item(session, track_id).wait()
+        .map_err(|e| format!("Could not fetch metadata: {:?}", e))?;
+
+    let file_id = pick_file(&item, bitrate)
+        .ok_or_else(|| "Track has no file in a supported format".to_string())?;
+
+    let key = session.audio_key().request(item.id, file_id).wait()
+        .map_err(|e| format!("Could not fetch audio key: {:?}", e))?;
+
+    Ok((file_id, key))
+}
+
+// Reads the gain and peak from the cached copy of `file_id`, None while the player is still downloading it
+fn read_cached(session: &Session, file_id: FileId, key: AudioKey) -> Result<Option<(f32, f32)>, String> {
+    let file = match session.cache().and_then(|cache| cache.file(file_id)) {
+        Some(file) => file,
+        None => return Ok(None)
+    };
+
+    let mut decrypted = AudioDecrypt::new(key, file);
+    let mut header = [0u8; 8];
+
+    decrypted.seek(SeekFrom::Start(NORMALISATION_HEADER_OFFSET))
+        .and_then(|_| decrypted.read_exact(&mut header))
+        .map_err(|e| format!("Could not read normalisation data: {}", e))?;
+
+    let track_gain_db = f32::from_bits(u32::from_le_bytes([header[0], header[1], header[2], header[3]]));
+    let track_peak = f32::from_bits(u32::from_le_bytes([header[4], header[5], header[6], header[7]]));
+
+    Ok(Some((track_gain_db, track_peak)))
+}
+
+// The file for the configured bitrate, or whichever other Vorbis file there is
+fn pick_file(item: &AudioItem, bitrate: Bitrate) -> Option<FileId> {
+    let preferred = match bitrate {
+        Bitrate::Bitrate96 => FileFormat::OGG_VORBIS_96,
+        Bitrate::Bitrate160 => FileFormat::OGG_VORBIS_160,
+        Bitrate::Bitrate320 => FileFormat::OGG_VORBIS_320
+    };
+
+    let formats = [preferred, FileFormat::OGG_VORBIS_160, FileFormat::OGG_VORBIS_96, FileFormat::OGG_VORBIS_320];
+
+    formats.iter()
+        .filter_map(|format| item.files.get(format).cloned())
+        .next()
+}
@@ -0,0 +1,241 @@
+use std::f64::consts::PI;
+
+use super::pacing::DECODER_SAMPLE_RATE;
+
+const CHANNELS: usize = 2;
+
+// Rates the sink converts to. Each one shares a large enough divisor with 44.1 kHz
+// that the filter bank stays small.
+pub const SUPPORTED_SAMPLE_RATES: [u32; 11] = [8000, 11025, 16000, 22050, 24000, 32000, 44100, 48000, 88200, 96000, 192000];
+
+// Zero crossings of the sinc on either side of the center, at the input rate
+const ZERO_CROSSINGS: f64 = 64.0;
+// Cutoff relative to the lower of the two Nyquist frequencies, leaves room for the transition band
+const ROLLOFF: f64 = 0.96;
+// Kaiser window shape, about 80 dB of stopband attenuation
+const KAISER_BETA: f64 = 8.0;
+
+// Band-limited sample rate conversion of interleaved stereo from the decoder rate, by a
+// windowed sinc evaluated at the exact rational position of each output frame.
+// Input that isn't covered by the filter yet is kept for the next call, so chunks, and
+// the tracks they belong to, join up without clicks.
+pub struct Resampler {
+    // Output frames per `step` input frames, reduced, which is also the number of filter phases
+    phases: usize,
+    step: usize,
+    taps: usize,
+    // `phases` rows of `taps` coefficients
+    filter: Vec<f32>,
+    // Interleaved input still needed, starting at the first tap of the next output frame
+    pending: Vec<f32>,
+    phase: usize
+}
+
+impl Resampler {
+    pub fn new(output_rate: u32) -> Resampler {
+        let input_rate = DECODER_SAMPLE_RATE as usize;
+        let output_rate = output_rate as usize;
+
+        let divisor = gcd(input_rate, output_rate);
+        let phases = output_rate / divisor;
+        let step = input_rate / divisor;
+
+        // Downsampling moves the cutoff below the output Nyquist, which widens the filter
+        let scale = (output_rate as f64 / input_rate as f64).min(1.0);
+        let cutoff = scale * ROLLOFF;
+        let half = (ZERO_CROSSINGS / scale).ceil() as usize;
+        let taps = 2 * half;
+
+        let mut filter = Vec::with_capacity(phases * taps);
+
+        for phase in 0..phases {
+            let offset = phase as f64 / phases as f64;
+
+            let row: Vec<f64> = (0..taps).map(|tap| {
+                // Distance of the tap from the output frame, in input frames
+                let x = tap as f64 - (half as f64 - 1.0) - offset;
+
+                cutoff * sinc(cutoff * x) * kaiser(x / half as f64)
+            }).collect();
+
+            // Unity gain at DC for every phase, otherwise the phases ripple against each other
+            let sum: f64 = row.iter().sum();
+
+            filter.extend(row.iter().map(|coefficient| (coefficient / sum) as f32));
+        }
+
+        Resampler {
+            phases,
+            step,
+            taps,
+            filter,
+            // The first output frame lines up with the first input frame, silence before it
+            pending: vec![0.0; (half - 1) * CHANNELS],
+            phase: 0
+        }
+    }
+
+    // Returns the output for `data`, which may come a few frames later than the input it belongs to
+    pub fn process(&mut self, data: &[i16]) -> Vec<i16> {
+        // Same rate, nothing to filter
+        if self.phases == self.step {
+            return data.to_vec();
+        }
+
+        self.pending.extend(data.iter().map(|sample| *sample as f32));
+
+        let available = self.pending.len() / CHANNELS;
+        let mut output = Vec::with_capacity((data.len() * self.phases / self.step) + CHANNELS);
+        let mut first = 0;
+
+        while first + self.taps <= available {
+            let row = &self.filter[self.phase * self.taps..(self.phase + 1) * self.taps];
+            let frames = &self.pending[first * CHANNELS..(first + self.taps) * CHANNELS];
+
+            let mut left = 0.0;
+            let mut right = 0.0;
+
+            for (coefficient, frame) in row.iter().zip(frames.chunks(CHANNELS)) {
+                left += coefficient * frame[0];
+                right += coefficient * frame[1];
+            }
+
+            output.push(to_sample(left));
+            output.push(to_sample(right));
+
+            self.phase += self.step;
+            first += self.phase / self.phases;
+            self.phase %= self.phases;
+        }
+
+        self.pending.drain(..first * CHANNELS);
+
+        output
+    }
+}
+
+fn to_sample(value: f32) -> i16 {
+    value.round().max(i16::min_value() as f32).min(i16::max_value() as f32) as i16
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+// `x` runs from -1 to 1 across the window
+fn kaiser(x: f64) -> f64 {
+    if x.abs() > 1.0 {
+        return 0.0;
+    }
+
+    bessel_i0(KAISER_BETA * (1.0 - x * x).sqrt()) / bessel_i0(KAISER_BETA)
+}
+
+// Zeroth order modified Bessel function of the first kind, by its power series
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut k = 1.0;
+
+    while term > sum * 1e-12 {
+        term *= (x / (2.0 * k)) * (x / (2.0 * k));
+        sum += term;
+        k += 1.0;
+    }
+
+    sum
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f64, frames: usize) -> Vec<i16> {
+        (0..frames).flat_map(|frame| {
+            let sample = ((2.0 * PI * frequency * frame as f64 / DECODER_SAMPLE_RATE as f64).sin() * 16000.0) as i16;
+
+            vec![sample, sample]
+        }).collect()
+    }
+
+    // Of the left channel, skipping the first `skip` frames the filter needs to settle
+    fn rms(samples: &[i16], skip: usize) -> f64 {
+        let left: Vec<f64> = samples.iter().step_by(CHANNELS).skip(skip).map(|sample| *sample as f64).collect();
+
+        (left.iter().map(|sample| sample * sample).sum::<f64>() / left.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn passes_the_decoder_rate_through() {
+        let input = sine(1000.0, 4410);
+
+        let mut resampler = Resampler::new(DECODER_SAMPLE_RATE as u32);
+
+        assert_eq!(resampler.process(&input), input);
+    }
+
+    #[test]
+    fn produces_frames_at_the_output_rate() {
+        let frames = DECODER_SAMPLE_RATE as usize;
+
+        for &rate in SUPPORTED_SAMPLE_RATES.iter() {
+            let mut resampler = Resampler::new(rate);
+
+            let output = resampler.process(&sine(1000.0, frames)).len() / CHANNELS;
+
+            // Less the frames still held back for the filter
+            let held = resampler.taps * rate as usize / DECODER_SAMPLE_RATE as usize + 1;
+
+            assert!(output <= rate as usize && output + held >= rate as usize, "{} Hz gave {} frames", rate, output);
+        }
+    }
+
+    #[test]
+    fn joins_chunks_seamlessly() {
+        let input = sine(1000.0, 20000);
+
+        let whole = Resampler::new(48000).process(&input);
+
+        let mut resampler = Resampler::new(48000);
+        let mut chunked = Vec::new();
+
+        for chunk in input.chunks(CHANNELS * 1023) {
+            chunked.extend(resampler.process(chunk));
+        }
+
+        assert_eq!(chunked, whole);
+    }
+
+    #[test]
+    fn keeps_the_level_of_the_passband() {
+        for &rate in [8000, 32000, 48000, 96000].iter() {
+            let mut resampler = Resampler::new(rate);
+
+            let output = resampler.process(&sine(1000.0, DECODER_SAMPLE_RATE as usize / 2));
+            let level = rms(&output, resampler.taps);
+
+            assert!((level - 16000.0 / 2f64.sqrt()).abs() < 150.0, "{} Hz came out at {}", rate, level);
+        }
+    }
+
+    #[test]
+    fn filters_what_the_output_rate_cant_hold() {
+        let mut resampler = Resampler::new(8000);
+
+        let output = resampler.process(&sine(6000.0, DECODER_SAMPLE_RATE as usize / 2));
+
+        assert!(rms(&output, resampler.taps) < 50.0);
+    }
+}
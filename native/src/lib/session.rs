@@ -0,0 +1,183 @@
+use tokio_core::reactor::{ Core, Remote };
+
+use futures::Future;
+use futures::sync::oneshot;
+use std::{ fmt, thread };
+use std::any::Any;
+use std::panic::{ self, AssertUnwindSafe };
+
+use librespot::core::authentication::Credentials;
+use librespot::core::cache::Cache;
+use librespot::core::config::SessionConfig;
+use librespot::core::session::Session;
+use librespot::protocol::authentication::AuthenticationType;
+
+use super::errors::ErrorCode;
+
+#[derive(Debug, Clone)]
+pub enum SessionError {
+    BadCredentials,
+    InvalidToken,
+    PremiumRequired,
+    Network(String),
+    // Discovery was stopped before anyone connected
+    Stopped,
+    Other(String)
+}
+
+impl ErrorCode for SessionError {
+    fn code(&self) -> &'static str {
+        match self {
+            SessionError::BadCredentials => "BAD_CREDENTIALS",
+            SessionError::InvalidToken => "INVALID_TOKEN",
+            SessionError::PremiumRequired => "PREMIUM_REQUIRED",
+            SessionError::Network(_) => "NETWORK",
+            SessionError::Stopped => "STOPPED",
+            SessionError::Other(_) => "UNKNOWN"
+        }
+    }
+}
+
+impl SessionError {
+    // librespot panics with the `APLoginFailed` error code when the access point
+    // refuses the login, so the only place the reason survives is the panic message.
+    fn from_panic(payload: Box<dyn Any + Send>) -> SessionError {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&'static str>() {
+                Ok(message) => message.to_string(),
+                Err(_) => "Session thread panicked".to_string()
+            }
+        };
+
+        if message.contains("BadCredentials") || message.contains("CouldNotValidateCredentials") {
+            SessionError::BadCredentials
+        } else if message.contains("PremiumAccountRequired") {
+            SessionError::PremiumRequired
+        } else {
+            SessionError::Other(message)
+        }
+    }
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::BadCredentials => write!(f, "Bad credentials"),
+            SessionError::InvalidToken => write!(f, "Access token was rejected, it is either invalid or expired"),
+            SessionError::PremiumRequired => write!(f, "Spotify Premium is required"),
+            SessionError::Network(e) => write!(f, "Network error: {}", e),
+            SessionError::Stopped => write!(f, "Discovery stopped before anyone connected"),
+            SessionError::Other(e) => write!(f, "Session error: {}", e)
+        }
+    }
+}
+
+pub fn auth_type_name(auth_type: AuthenticationType) -> &'static str {
+    match auth_type {
+        AuthenticationType::AUTHENTICATION_USER_PASS => "AUTHENTICATION_USER_PASS",
+        AuthenticationType::AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS => "AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS",
+        AuthenticationType::AUTHENTICATION_STORED_FACEBOOK_CREDENTIALS => "AUTHENTICATION_STORED_FACEBOOK_CREDENTIALS",
+        AuthenticationType::AUTHENTICATION_SPOTIFY_TOKEN => "AUTHENTICATION_SPOTIFY_TOKEN",
+        AuthenticationType::AUTHENTICATION_FACEBOOK_TOKEN => "AUTHENTICATION_FACEBOOK_TOKEN"
+    }
+}
+
+pub fn parse_auth_type(name: &str) -> Option<AuthenticationType> {
+    match name {
+        "AUTHENTICATION_USER_PASS" => Some(AuthenticationType::AUTHENTICATION_USER_PASS),
+        "AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS" => Some(AuthenticationType::AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS),
+        "AUTHENTICATION_STORED_FACEBOOK_CREDENTIALS" => Some(AuthenticationType::AUTHENTICATION_STORED_FACEBOOK_CREDENTIALS),
+        "AUTHENTICATION_SPOTIFY_TOKEN" => Some(AuthenticationType::AUTHENTICATION_SPOTIFY_TOKEN),
+        "AUTHENTICATION_FACEBOOK_TOKEN" => Some(AuthenticationType::AUTHENTICATION_FACEBOOK_TOKEN),
+        _ => None
+    }
+}
+
+// A connected session together with the `Core` thread driving it.
+// The thread is stopped once this is dropped.
+pub struct Connection {
+    pub remote: Remote,
+    pub session: Session,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>
+}
+
+impl Connection {
+    // Only a session that died on its own counts as lost, not one we shut down
+    pub fn is_lost(&self) -> bool {
+        self.shutdown_tx.is_some() && self.session.is_invalid()
+    }
+
+    pub fn shutdown(&mut self) {
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            self.session.shutdown();
+
+            let _ = shutdown_tx.send(());
+        }
+
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Session thread panicked during shutdown");
+            }
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// Spawns the background `Core` thread and connects a session on it.
+// Blocks the calling thread until the handshake is done, so never call this from the Node main thread.
+pub fn connect(session_config: SessionConfig, credentials: Credentials, cache: Option<Cache>) -> Result<Connection, SessionError> {
+    let (session_tx, session_rx) = oneshot::channel();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+    let token_login = credentials.auth_type == AuthenticationType::AUTHENTICATION_SPOTIFY_TOKEN;
+
+    let thread = thread::spawn(move || {
+        let mut core = Core::new().unwrap();
+
+        let handle = core.handle();
+        let remote = handle.remote().clone();
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            core.run(Session::connect(
+                session_config,
+                credentials,
+                cache,
+                handle.clone()))
+        }));
+
+        let result = match result {
+            Ok(Ok(session)) => Ok((remote, session)),
+            Ok(Err(e)) => Err(SessionError::Network(e.to_string())),
+            Err(payload) => match SessionError::from_panic(payload) {
+                SessionError::BadCredentials if token_login => Err(SessionError::InvalidToken),
+                e => Err(e)
+            }
+        };
+
+        let connected = result.is_ok();
+
+        let _ = session_tx.send(result);
+
+        if connected {
+            let _ = core.run(shutdown_rx);
+        }
+    });
+
+    match session_rx.wait() {
+        Ok(Ok((remote, session))) => Ok(Connection {
+            remote,
+            session,
+            shutdown_tx: Some(shutdown_tx),
+            thread: Some(thread)
+        }),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(SessionError::Other("Session thread exited unexpectedly".to_string()))
+    }
+}
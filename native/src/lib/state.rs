@@ -0,0 +1,126 @@
+use librespot::core::spotify_id::SpotifyId;
+use librespot::playback::player::PlayerEvent;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlaybackStatus {
+    Stopped,
+    Loading,
+    Playing,
+    Paused
+}
+
+impl PlaybackStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PlaybackStatus::Stopped => "stopped",
+            PlaybackStatus::Loading => "loading",
+            PlaybackStatus::Playing => "playing",
+            PlaybackStatus::Paused => "paused"
+        }
+    }
+}
+
+// What the active player is doing. The position is anchored at the last event that reported one
+// and moves with the frames that went through the sink since, not with the wall clock.
+pub struct PlaybackState {
+    track_id: Option<SpotifyId>,
+    status: PlaybackStatus,
+    duration_ms: u32,
+    position_ms: u32,
+    anchor_frames: u64,
+    // Of the frames counted by the sink
+    sample_rate: u64
+}
+
+#[derive(Debug, Clone)]
+pub struct PlaybackSnapshot {
+    pub track_id: Option<SpotifyId>,
+    pub status: PlaybackStatus,
+    pub duration_ms: u32,
+    pub position_ms: u32
+}
+
+impl PlaybackState {
+    pub fn new(sample_rate: u64) -> PlaybackState {
+        PlaybackState {
+            track_id: None,
+            status: PlaybackStatus::Stopped,
+            duration_ms: 0,
+            position_ms: 0,
+            anchor_frames: 0,
+            sample_rate
+        }
+    }
+
+    // `frames` is the number of frames written by the sink when the event came in
+    pub fn update(&mut self, event: &PlayerEvent, frames: u64) {
+        match *event {
+            PlayerEvent::Loading { track_id, position_ms, .. } => {
+                self.track_id = Some(track_id);
+                self.status = PlaybackStatus::Loading;
+                self.duration_ms = 0;
+                self.anchor(position_ms, frames);
+            },
+            PlayerEvent::Started { track_id, position_ms, .. } => {
+                self.track_id = Some(track_id);
+                self.anchor(position_ms, frames);
+            },
+            PlayerEvent::Playing { track_id, position_ms, duration_ms, .. } => {
+                self.track_id = Some(track_id);
+                self.status = PlaybackStatus::Playing;
+                self.duration_ms = duration_ms;
+                self.anchor(position_ms, frames);
+            },
+            PlayerEvent::Paused { track_id, position_ms, duration_ms, .. } => {
+                self.track_id = Some(track_id);
+                self.status = PlaybackStatus::Paused;
+                self.duration_ms = duration_ms;
+                self.anchor(position_ms, frames);
+            },
+            PlayerEvent::Changed { new_track_id, .. } => {
+                self.track_id = Some(new_track_id);
+            },
+            PlayerEvent::EndOfTrack { .. } => {
+                self.status = PlaybackStatus::Stopped;
+                self.anchor(self.duration_ms, frames);
+            },
+            PlayerEvent::Stopped { .. } | PlayerEvent::Unavailable { .. } => {
+                *self = PlaybackState::new(self.sample_rate);
+            },
+            _ => {}
+        }
+    }
+
+    fn anchor(&mut self, position_ms: u32, frames: u64) {
+        self.position_ms = position_ms;
+        self.anchor_frames = frames;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.status == PlaybackStatus::Playing
+    }
+
+    pub fn position_ms(&self, frames: u64) -> u32 {
+        if !self.is_playing() {
+            return self.position_ms;
+        }
+
+        let played_ms = (frames.saturating_sub(self.anchor_frames) * 1000 / self.sample_rate) as u32;
+        let position_ms = self.position_ms.saturating_add(played_ms);
+
+        if self.duration_ms > 0 && position_ms > self.duration_ms {
+            self.duration_ms
+        } else {
+            position_ms
+        }
+    }
+
+    pub fn snapshot(&self, frames: u64) -> PlaybackSnapshot {
+        PlaybackSnapshot {
+            track_id: self.track_id,
+            status: self.status,
+            duration_ms: self.duration_ms,
+            position_ms: self.position_ms(frames)
+        }
+    }
+}
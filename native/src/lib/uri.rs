@@ -0,0 +1,111 @@
+use librespot::core::spotify_id::{ SpotifyAudioType, SpotifyId };
+
+const SHARE_HOST: &str = "open.spotify.com/";
+// Base62 ids are always this long, `SpotifyId::from_base62` doesn't check
+const ID_LENGTH: usize = 22;
+
+// Parses what users paste to play something: a bare base62 track id, a `spotify:track:` or
+// `spotify:episode:` URI, or an open.spotify.com share link, with or without its query string
+pub fn parse_media_id(input: &str) -> Result<SpotifyId, String> {
+    let input = input.trim();
+
+    let (kind, id) = if input.starts_with("spotify:") {
+        let parts: Vec<&str> = input.split(':').collect();
+
+        match parts.as_slice() {
+            [_, kind, id] => (*kind, *id),
+            _ => return Err(format!("Invalid Spotify URI: {}", input))
+        }
+    } else if let Some(start) = input.find(SHARE_HOST) {
+        let path = &input[start + SHARE_HOST.len()..];
+        let path = path.split(|c| c == '?' || c == '#').next().unwrap_or("");
+
+        // Localized links look like /intl-de/track/<id>
+        let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty() && !part.starts_with("intl-")).collect();
+
+        match parts.as_slice() {
+            [kind, id] => (*kind, *id),
+            _ => return Err(format!("Invalid Spotify link: {}", input))
+        }
+    } else {
+        ("track", input)
+    };
+
+    let audio_type = match kind {
+        "track" => SpotifyAudioType::Track,
+        "episode" => SpotifyAudioType::Podcast,
+        _ => return Err(format!("Only tracks and episodes can be played, got a {}", kind))
+    };
+
+    if id.len() != ID_LENGTH || base62_value(id).is_none() {
+        return Err(format!("Invalid Spotify id: {}", id));
+    }
+
+    let mut media_id = SpotifyId::from_base62(id).map_err(|_| format!("Invalid Spotify id: {}", id))?;
+
+    media_id.audio_type = audio_type;
+
+    Ok(media_id)
+}
+
+// Same digits as `SpotifyId::from_base62`, which doesn't check for overflow either.
+// None for a character outside them, or a value that doesn't fit in 128 bits.
+fn base62_value(id: &str) -> Option<u128> {
+    id.bytes().try_fold(0u128, |value, c| {
+        let digit = match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'z' => c - b'a' + 10,
+            b'A'..=b'Z' => c - b'A' + 36,
+            _ => return None
+        };
+
+        value.checked_mul(62)?.checked_add(digit as u128)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "6rqhFgbbKwnb9MLmUQDhG6";
+
+    #[test]
+    fn parses_bare_ids_uris_and_links() {
+        let expected = SpotifyId::from_base62(ID).unwrap();
+
+        assert_eq!(parse_media_id(ID).unwrap(), expected);
+        assert_eq!(parse_media_id(&format!("spotify:track:{}", ID)).unwrap(), expected);
+        assert_eq!(parse_media_id(&format!("https://open.spotify.com/intl-de/track/{}?si=abc#x", ID)).unwrap(), expected);
+    }
+
+    #[test]
+    fn keeps_episodes_apart() {
+        let id = parse_media_id(&format!("spotify:episode:{}", ID)).unwrap();
+
+        assert_eq!(id.audio_type, SpotifyAudioType::Podcast);
+    }
+
+    #[test]
+    fn rejects_ids_of_the_wrong_length() {
+        assert!(parse_media_id("").is_err());
+        assert!(parse_media_id("spotify:track:").is_err());
+        assert!(parse_media_id(&format!("{}A", ID)).is_err());
+        assert!(parse_media_id(&ID[1..]).is_err());
+        // Above u128::MAX, which is 7N42dgm5tFLK9N8MT7fHC7
+        assert!(parse_media_id("zzzzzzzzzzzzzzzzzzzzzz").is_err());
+        assert!(parse_media_id("7N42dgm5tFLK9N8MT7fHC8").is_err());
+        assert!(parse_media_id("7N42dgm5tFLK9N8MT7fHC7").is_ok());
+    }
+
+    #[test]
+    fn rejects_non_base62_ids() {
+        assert!(parse_media_id("6rqhFgbbKwnb9MLmUQDhG!").is_err());
+        assert!(parse_media_id("6rqhFgbbKwnb9MLmUQDhGé").is_err());
+    }
+
+    #[test]
+    fn rejects_other_kinds() {
+        assert!(parse_media_id(&format!("spotify:album:{}", ID)).is_err());
+        assert!(parse_media_id(&format!("https://open.spotify.com/playlist/{}", ID)).is_err());
+    }
+}
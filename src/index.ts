@@ -1,21 +1,81 @@
 import { TypedEmitter } from 'tiny-typed-emitter';
 import envPaths from 'env-paths'
 import {PassThrough} from 'stream'
-import { Spotify, TNativeSpotifyEvent } from '../native';
-import {ISpotifyOptions, ESpotifyQuality, ISpotifyConnectOptions, ISpotifyEvents} from './types';
+import { AccessToken, Spotify as NativeSpotify, TNativeSpotifyEvent, IAudioDataEvent, IAudioRingEvent, IFormatEvent, Discovery as NativeDiscovery, connect, discover } from '../native';
+import {ISpotifyOptions, ISpotifyDiscoveryOptions, ESpotifyQuality, ISpotifyConnectOptions, ISpotifyEvents, ESpotifyConnectError, ESpotifyConnectCommand, ESpotifyPlaybackStatus, ISpotifyState, ISpotifyLoadOptions, ESpotifyCommandError, ISpotifyCommandResult, ISpotifyQueue, ESpotifyRepeatMode, ISpotifyPlayerConfig, ISpotifyAudioFormat} from './types';
 import fs from 'fs';
 
-export default class extends TypedEmitter<ISpotifyEvents> {
+export class SpotifyConnectError extends Error {
+    constructor(message: string, public code: ESpotifyConnectError) {
+        super(message);
+        this.name = 'SpotifyConnectError';
+    }
+}
+
+export class SpotifyCommandError extends Error {
+    constructor(message: string, public code: ESpotifyCommandError) {
+        super(message);
+        this.name = 'SpotifyCommandError';
+    }
+}
+
+export default class Spotify extends TypedEmitter<ISpotifyEvents> {
     public stream: PassThrough = new PassThrough();
-    private native: Spotify;
+    private native: NativeSpotify;
 
-    constructor(options: ISpotifyOptions) {
-        super();
+    // Of the audio in `stream` and the 'audio' events
+    get sampleRate(): number {
+        return this.native.sampleRate;
+    }
+
+    // Sample format and channel layout of the same audio
+    get format(): ISpotifyAudioFormat {
+        return this.native.format;
+    }
+
+    static async connect(options: ISpotifyOptions): Promise<Spotify> {
+        const settings = Spotify.withDefaults(options);
+
+        const native = await new Promise<NativeSpotify>((resolve, reject) => {
+            connect(settings, (error, spotify) => {
+                if (error) {
+                    return reject(new SpotifyConnectError(error.message, error.code));
+                }
+
+                resolve(spotify);
+            });
+        });
+
+        return new Spotify(native);
+    }
+
+    // Announces a Connect device on the LAN and resolves once a phone hands over its credentials.
+    // stop() gives up waiting, the promise then rejects with code STOPPED.
+    static discover(options: ISpotifyDiscoveryOptions): Promise<Spotify> & { stop(): void } {
+        const settings = Spotify.withDefaults(options);
+
+        let discovery: NativeDiscovery;
+
+        const spotify = new Promise<NativeSpotify>((resolve, reject) => {
+            discovery = discover(settings, (error, spotify) => {
+                if (error) {
+                    return reject(new SpotifyConnectError(error.message, error.code));
+                }
+
+                resolve(spotify);
+            });
+        }).then((native) => new Spotify(native));
+
+        return Object.assign(spotify, {
+            stop: () => discovery.stop()
+        });
+    }
 
+    private static withDefaults<T extends {quality?: ESpotifyQuality, cacheDir?: string}>(options: T): T {
         // TODO: check cache path if provided
         const paths = envPaths('librespot-node');
 
-        const settings: ISpotifyOptions = Object.assign({}, {
+        const settings: T = Object.assign({}, {
             quality: ESpotifyQuality.Bitrate160,
             cacheDir: paths.cache
         }, options);
@@ -26,9 +86,16 @@ export default class extends TypedEmitter<ISpotifyEvents> {
             }
         } catch {}
 
-        this.native = new Spotify(settings);
+        return settings;
+    }
+
+    private constructor(native: NativeSpotify) {
+        super();
+
+        this.native = native;
 
-        setInterval(() => this.native.poll(this.handleEvent.bind(this)), 5);
+        this.native.listen(this.handleEvent.bind(this));
+        this.native.listenAudio(this.handleAudio.bind(this));
     }
 
     async enableConnect(options: ISpotifyConnectOptions) {
@@ -36,93 +103,324 @@ export default class extends TypedEmitter<ISpotifyEvents> {
     }
 
     async disableConnect() {
+        this.native.disableConnect();
+    }
 
+    // Remote control for the Connect device, throws if Connect is not enabled
+    async connectPlay() {
+        this.native.connectCommand(ESpotifyConnectCommand.Play);
     }
 
-    async play(trackId: string) {
-        this.native.play(trackId);
+    async connectPause() {
+        this.native.connectCommand(ESpotifyConnectCommand.Pause);
     }
 
-    async pause() { 
-        // this.native.
+    async connectPlayPause() {
+        this.native.connectCommand(ESpotifyConnectCommand.PlayPause);
     }
 
-    async stop() {
+    async connectNext() {
+        this.native.connectCommand(ESpotifyConnectCommand.Next);
+    }
 
+    async connectPrevious() {
+        this.native.connectCommand(ESpotifyConnectCommand.Previous);
     }
 
-    async seek(positionMs: number) {
+    async connectVolumeUp() {
+        this.native.connectCommand(ESpotifyConnectCommand.VolumeUp);
+    }
 
+    async connectVolumeDown() {
+        this.native.connectCommand(ESpotifyConnectCommand.VolumeDown);
+    }
+
+    // Resolves once the track plays, or is ready when loaded paused. Besides base62 track ids this takes
+    // spotify:track: and spotify:episode: URIs and open.spotify.com links, and throws on anything else
+    async play(trackId: string, options?: ISpotifyLoadOptions): Promise<ISpotifyCommandResult> {
+        return this.command(callback => this.native.play(trackId, options, callback));
+    }
+
+    // Transport for direct playback, these resolve once the player confirms and reject when no track is playing or paused
+    async pause(): Promise<ISpotifyCommandResult> {
+        return this.command(callback => this.native.pause(callback));
+    }
+
+    async resume(): Promise<ISpotifyCommandResult> {
+        return this.command(callback => this.native.resume(callback));
+    }
+
+    async toggle(): Promise<ISpotifyCommandResult> {
+        return this.command(callback => this.native.toggle(callback));
+    }
+
+    async stop(): Promise<ISpotifyCommandResult> {
+        return this.command(callback => this.native.stop(callback));
+    }
+
+    // Resolves with the position the player seeked to
+    async seek(positionMs: number): Promise<ISpotifyCommandResult> {
+        return this.command(callback => this.native.seek(positionMs, callback));
+    }
+
+    // Relative to the current position, negative to go back
+    async seekBy(deltaMs: number): Promise<ISpotifyCommandResult> {
+        return this.command(callback => this.native.seekBy(deltaMs, callback));
+    }
+
+    // 0-1 of the track duration
+    async seekFraction(fraction: number): Promise<ISpotifyCommandResult> {
+        return this.command(callback => this.native.seekFraction(fraction, callback));
+    }
+
+    async restart(): Promise<ISpotifyCommandResult> {
+        return this.command(callback => this.native.restart(callback));
+    }
+
+    // Has the player fetch the track in the background, so a following play() starts right away. 'preloaded' follows
+    // once the whole file is fetched. Failures show up as 'unavailable' on play()
+    preload(trackId: string) {
+        this.native.preload(trackId);
+    }
+
+    // The queue plays on its own once started with next(), play() doesn't touch it
+    enqueue(...trackIds: string[]) {
+        this.native.enqueue(trackIds);
+    }
+
+    insertNext(...trackIds: string[]) {
+        this.native.insertNext(trackIds);
+    }
+
+    removeFromQueue(index: number) {
+        this.native.removeFromQueue(index);
+    }
+
+    moveInQueue(from: number, to: number) {
+        this.native.moveInQueue(from, to);
+    }
+
+    clearQueue() {
+        this.native.clearQueue();
+    }
+
+    setShuffle(shuffle: boolean) {
+        this.native.setShuffle(shuffle);
+    }
+
+    setRepeat(repeat: ESpotifyRepeatMode) {
+        this.native.setRepeat(repeat);
+    }
+
+    getQueue(): ISpotifyQueue {
+        return this.native.getQueue();
+    }
+
+    async next(): Promise<ISpotifyCommandResult> {
+        return this.command(callback => this.native.next(callback));
+    }
+
+    // Restarts the current entry instead when it has played for more than 3 seconds
+    async previous(): Promise<ISpotifyCommandResult> {
+        return this.command(callback => this.native.previous(callback));
+    }
+
+    // 0-65535, mapped through the volumeCtrl curve
+    async setVolume(volume: number) {
+        this.native.setVolume(volume);
+    }
+
+    // Applies to the next track that is loaded, without reconnecting
+    setPlayerConfig(config: ISpotifyPlayerConfig) {
+        this.native.setPlayerConfig(config);
+    }
+
+    // 0-65535 as last set, here or through Connect, before the volume curve. Same scale as 'volume-set'
+    async getVolume(): Promise<number> {
+        return this.native.getVolume();
+    }
+
+    getState(): ISpotifyState {
+        return this.native.getState();
     }
 
     async getPosition(): Promise<number> {
-        return 0;
+        return this.getState().positionMs;
     }
 
+    // Empty when nothing is loaded
     async getCurrentTrack(): Promise<string> {
-        // TODO: use property instead?
-        return ''
+        return this.getState().trackId || '';
+    }
+
+    // Web API token of the logged in user, e.g. for fetching metadata
+    async getToken(clientId: string, scopes: string): Promise<AccessToken> {
+        return new Promise<AccessToken>((resolve, reject) => {
+            this.native.getToken(clientId, scopes, (token) => {
+                if (!token) {
+                    return reject(new Error('Could not get a token'));
+                }
+
+                resolve(token);
+            });
+        });
     }
 
     async isPlaying(): Promise<boolean> {
-        return false;
+        return this.getState().status === ESpotifyPlaybackStatus.Playing;
     }
 
+    // Has to be called when done, the native event listeners keep the process running until then.
+    // They also keep the instance from being garbage collected, so nothing else cleans it up
     async teardown() {
+        this.native.shutdown();
+        this.stream.end();
+    }
+
+    // Native commands throw right away on invalid state, and call back once the player confirms
+    private command(run: (callback: (error: (Error & {code: ESpotifyCommandError}) | null, result: ISpotifyCommandResult) => void) => void): Promise<ISpotifyCommandResult> {
+        return new Promise((resolve, reject) => {
+            run((error, result) => {
+                if (error) {
+                    return reject(new SpotifyCommandError(error.message, error.code));
+                }
 
+                resolve(result);
+            });
+        });
     }
 
-    private handleEvent(error: Error | null, event: TNativeSpotifyEvent | null ) {
-        if (!event) {
-            return;
+    // Acknowledged once the chunk has passed through the stream, which waits for the reader when
+    // the stream is full. Only consumer pacing blocks on this.
+    private handleAudio(event: IAudioDataEvent | IAudioRingEvent | IFormatEvent) {
+        if (event.name === 'format') {
+            const {name, sampleOffset, ...format} = event;
+
+            return this.emit('format', format);
         }
 
-        console.log(event)
+        if (event.name === 'audio-ring') {
+            return this.handleAudioRing(event);
+        }
+
+        const frames = event.data.length / this.native.format.bytesPerFrame;
+
+        this.stream.write(event.data, () => {
+            try {
+                this.native.acknowledgeAudio(frames);
+            } catch {}
+        });
+    }
+
+    // The ring region is only ours until it's released, so listeners have to be done with it on return
+    private handleAudioRing(event: IAudioRingEvent) {
+        const bytesPerFrame = this.native.format.bytesPerFrame;
+        const data = Buffer.from(this.native.audioRing!, event.offset * bytesPerFrame, event.frames * bytesPerFrame);
+
+        try {
+            this.emit('audio', {data, sampleOffset: event.sampleOffset});
+        } finally {
+            try {
+                this.native.acknowledgeAudio(event.frames);
+            } catch {}
+        }
+    }
 
+    private handleEvent(event: TNativeSpotifyEvent) {
         switch (event.name) {
-            case "audio-data":
-                this.stream.write(event.data);
-                break;
             case "started":
                 this.emit('started', {
                     trackId: event.trackId,
-                    positionMs: event.positionMs
+                    playRequestId: event.playRequestId,
+                    positionMs: event.positionMs,
+                    sampleOffset: event.sampleOffset
                 });
                 break;
             case "stopped":
-                this.emit('stopped', {trackId: event.trackId});
+                this.emit('stopped', {trackId: event.trackId, playRequestId: event.playRequestId, sampleOffset: event.sampleOffset});
                 break;
             case "changed":
-                this.emit('track-change', {oldTrackId: event.oldTrackId, newTrackId: event.newTrackId});
+                this.emit('track-change', {oldTrackId: event.oldTrackId, newTrackId: event.newTrackId, sampleOffset: event.sampleOffset});
                 break;
             case "loading":
-                this.emit('loading', {trackId: event.trackId, positionMs: event.positionMs});
+                this.emit('loading', {trackId: event.trackId, playRequestId: event.playRequestId, positionMs: event.positionMs, sampleOffset: event.sampleOffset});
                 break;
             case 'playing':
                 this.emit('playing', {
                     trackId: event.trackId,
+                    playRequestId: event.playRequestId,
                     positionMs: event.positionMs,
-                    durationMs: event.durationMs
+                    durationMs: event.durationMs,
+                    replayGain: event.replayGain,
+                    sampleOffset: event.sampleOffset
+                });
+                break;
+            case 'replay-gain':
+                this.emit('replay-gain', {
+                    trackId: event.trackId,
+                    playRequestId: event.playRequestId,
+                    replayGain: event.replayGain,
+                    sampleOffset: event.sampleOffset
                 });
                 break;
             case 'paused':
                 this.emit('paused', {
                     trackId: event.trackId,
+                    playRequestId: event.playRequestId,
                     positionMs: event.positionMs,
-                    durationMs: event.durationMs
+                    durationMs: event.durationMs,
+                    sampleOffset: event.sampleOffset
                 })
                 break;
             case 'end-of-track':
-                this.emit('end-of-track', {trackId: event.trackId});
+                this.emit('end-of-track', {trackId: event.trackId, playRequestId: event.playRequestId, sampleOffset: event.sampleOffset});
                 break;
             case 'volume-set':
-                this.emit('volume-set', {volume: event.volume});
+                this.emit('volume-set', {volume: event.volume, sampleOffset: event.sampleOffset});
                 break;
             case 'time-to-preload-next-track':
-                this.emit('time-to-preload-next-track', {trackId: event.trackId});
+                this.emit('time-to-preload-next-track', {trackId: event.trackId, playRequestId: event.playRequestId, sampleOffset: event.sampleOffset});
                 break;
             case 'unavailable':
-                this.emit('unavailable', {trackId: event.trackId});
+                this.emit('unavailable', {trackId: event.trackId, playRequestId: event.playRequestId, sampleOffset: event.sampleOffset});
+                break;
+            case 'credentials':
+                this.emit('credentials', {
+                    username: event.username,
+                    authType: event.authType,
+                    authData: event.authData
+                });
+                break;
+            case 'session-lost':
+                this.emit('session-lost');
+                break;
+            case 'reconnecting':
+                this.emit('reconnecting', {attempt: event.attempt, delayMs: event.delayMs});
+                break;
+            case 'reconnected':
+                this.emit('reconnected');
+                break;
+            case 'preloaded':
+                this.emit('preloaded', {trackId: event.trackId, sampleOffset: event.sampleOffset});
+                break;
+            case 'queue-changed':
+                this.emit('queue-changed', {
+                    tracks: event.tracks,
+                    current: event.current,
+                    shuffle: event.shuffle,
+                    repeat: event.repeat
+                });
+                break;
+            case 'position':
+                this.emit('position', {
+                    trackId: event.trackId,
+                    positionMs: event.positionMs,
+                    durationMs: event.durationMs,
+                    sampleOffset: event.sampleOffset
+                });
+                break;
+            case 'user-connected':
+                this.emit('user-connected', {username: event.username});
                 break;
 
             default:
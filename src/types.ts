@@ -10,6 +10,55 @@ export enum ESpotifyVolumeCtrl {
   Fixed = 'Fixed'
 }
 
+export enum ESpotifyPacing {
+  // Audio is delivered at playback speed
+  Realtime = 'realtime',
+  // Decoding blocks until the stream has been read, up to audioBufferMs ahead
+  Consumer = 'consumer',
+  // As fast as possible, for offline processing
+  Free = 'free'
+}
+
+export enum ESpotifyAudioDelivery {
+  // A new Buffer per chunk, written to the stream
+  Copy = 'copy',
+  // Same, only the native side reuses its chunks. Every chunk still arrives in a new Buffer, ring avoids that
+  Recycled = 'recycled',
+  // Written in place into a shared ring buffer and handed out through 'audio' events, not the stream
+  Ring = 'ring'
+}
+
+export enum ESpotifySampleFormat {
+  S16LE = 's16le',
+  // Packed, three bytes per sample
+  S24LE = 's24le',
+  S32LE = 's32le',
+  // -1 to 1, what Web Audio takes
+  F32LE = 'f32le'
+}
+
+export enum ESpotifyChannelLayout {
+  Interleaved = 'interleaved',
+  // Each chunk holds all of its left samples, then all of its right ones
+  Planar = 'planar'
+}
+
+export enum ESpotifyChannels {
+  Stereo = 'stereo',
+  // Both channels averaged
+  Mono = 'mono'
+}
+
+// What the audio data looks like, frames hold one sample per channel
+export interface ISpotifyAudioFormat {
+  sampleFormat: ESpotifySampleFormat,
+  layout: ESpotifyChannelLayout,
+  channels: number,
+  sampleRate: number,
+  bytesPerSample: number,
+  bytesPerFrame: number
+}
+
 export enum ESpotifyConnectDeviceType {
   Unknown = 'Unknown',
   Computer = 'Computer',
@@ -22,11 +71,151 @@ export enum ESpotifyConnectDeviceType {
   AudioDongle = 'AudioDongle'
 }
 
-export interface ISpotifyOptions {
+export enum ESpotifyConnectError {
+  BadCredentials = 'BAD_CREDENTIALS',
+  InvalidToken = 'INVALID_TOKEN',
+  PremiumRequired = 'PREMIUM_REQUIRED',
+  Network = 'NETWORK',
+  // Discovery was stopped before anyone connected
+  Stopped = 'STOPPED',
+  Unknown = 'UNKNOWN'
+}
+
+export enum ESpotifyCommandError {
+  Unavailable = 'UNAVAILABLE',
+  // Stopped or replaced before it started playing
+  Interrupted = 'INTERRUPTED',
+  SessionLost = 'SESSION_LOST',
+  Timeout = 'TIMEOUT',
+  Closed = 'CLOSED'
+}
+
+export enum ESpotifyAuthType {
+  UserPass = 'AUTHENTICATION_USER_PASS',
+  StoredSpotifyCredentials = 'AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS',
+  StoredFacebookCredentials = 'AUTHENTICATION_STORED_FACEBOOK_CREDENTIALS',
+  SpotifyToken = 'AUTHENTICATION_SPOTIFY_TOKEN',
+  FacebookToken = 'AUTHENTICATION_FACEBOOK_TOKEN'
+}
+
+export interface ISpotifyCredentials {
   username: string,
-  password: string,
+  authType: ESpotifyAuthType,
+  // base64 encoded
+  authData: string
+}
+
+export interface ISpotifyTokenCredentials {
+  username?: string,
+  // OAuth access token, needs the 'streaming' scope
+  accessToken: string
+}
+
+// Applied to the next track the player loads when changed with setPlayerConfig()
+export interface ISpotifyPlayerConfig {
+  // Scales each track to the same loudness, off by default
+  normalisation?: boolean,
+  // Added to the track gain with normalisation, defaults to 0
+  normalisationPregainDb?: number,
+  // Defaults to true
+  gapless?: boolean
+}
+
+// The gain applied to a track with normalisation on
+export interface ISpotifyReplayGain {
+  trackGainDb: number,
+  trackPeak: number,
+  pregainDb: number,
+  // Linear factor the samples are scaled by, kept low enough for the peak not to clip
+  factor: number
+}
+
+export interface ISpotifyOptions extends ISpotifyPlayerConfig {
+  username?: string,
+  password?: string,
+  // Reusable credentials from a previous 'credentials' event, or an OAuth access token.
+  // Without password or credentials, the cache is used
+  credentials?: ISpotifyCredentials | ISpotifyTokenCredentials,
   quality?: ESpotifyQuality
-  cacheDir?: string
+  cacheDir?: string,
+  // 0-65535, applies to direct playback and is shared with Connect
+  initialVolume?: number,
+  // Volume curve used by setVolume()
+  volumeCtrl?: ESpotifyVolumeCtrl,
+  // Rate of the audio handed out, resampled from 44100 when different: 8000, 11025, 16000, 22050,
+  // 24000, 32000, 44100, 48000, 88200, 96000 or 192000
+  outputSampleRate?: number,
+  // The audio is s16le interleaved stereo unless set otherwise
+  outputSampleFormat?: ESpotifySampleFormat,
+  outputChannelLayout?: ESpotifyChannelLayout,
+  outputChannels?: ESpotifyChannels,
+  // Defaults to realtime
+  pacing?: ESpotifyPacing,
+  // Audio decoded ahead of the consumer with consumer pacing, defaults to 1000
+  audioBufferMs?: number,
+  // Defaults to copy
+  audioDelivery?: ESpotifyAudioDelivery,
+  // Size of the ring with ring delivery, defaults to 2000
+  audioRingMs?: number,
+  // Emits 'position' every positionIntervalMs of played audio, off by default
+  positionIntervalMs?: number,
+  // How long play(), pause(), seek() etc. wait for the player to confirm, defaults to 10000
+  commandTimeoutMs?: number
+}
+
+// What the player reported once a command went through
+export interface ISpotifyCommandResult {
+  playRequestId: number,
+  trackId: string,
+  positionMs: number,
+  durationMs: number
+}
+
+export enum ESpotifyRepeatMode {
+  Off = 'off',
+  // Auto-advance repeats the current entry, next() still skips
+  One = 'one',
+  All = 'all'
+}
+
+export interface ISpotifyQueue {
+  tracks: string[],
+  // Index of the playing entry, null before the queue has started
+  current: number | null,
+  shuffle: boolean,
+  repeat: ESpotifyRepeatMode
+}
+
+export interface ISpotifyLoadOptions {
+  // Defaults to 0
+  positionMs?: number,
+  // Loads the track without starting it, resume() starts it
+  paused?: boolean
+}
+
+export enum ESpotifyPlaybackStatus {
+  Stopped = 'stopped',
+  Loading = 'loading',
+  Playing = 'playing',
+  Paused = 'paused'
+}
+
+export interface ISpotifyState {
+  trackId: string | null,
+  status: ESpotifyPlaybackStatus,
+  // Counted from the audio that went through the output
+  positionMs: number,
+  durationMs: number
+}
+
+export enum ESpotifyConnectCommand {
+  Play = 'play',
+  Pause = 'pause',
+  PlayPause = 'play-pause',
+  Next = 'next',
+  Previous = 'previous',
+  VolumeUp = 'volume-up',
+  VolumeDown = 'volume-down'
 }
 
 export interface ISpotifyConnectOptions {
@@ -36,15 +225,37 @@ export interface ISpotifyConnectOptions {
   volumeCtrl: ESpotifyVolumeCtrl
 }
 
+export interface ISpotifyDiscoveryOptions extends ISpotifyConnectOptions {
+  quality?: ESpotifyQuality,
+  cacheDir?: string,
+  // Port for the zeroconf `addUser` endpoint, random when omitted
+  discoveryPort?: number
+}
+
+// Player events carry `sampleOffset`: the number of audio frames written to the stream before the event
 export interface ISpotifyEvents {
-  'started': ({trackId, positionMs}: {trackId: string, positionMs: number}) => void;
-  'stopped': ({trackId}: {trackId: string}) => void;
-  'loading': ({trackId, positionMs}: {trackId: string, positionMs: number}) => void;
-  'playing': ({trackId, positionMs, durationMs}: {trackId: string, positionMs: number, durationMs: number}) => void;
-  'paused': ({trackId, positionMs, durationMs}: {trackId: string, positionMs: number, durationMs: number}) => void;
-  'end-of-track': ({trackId}: {trackId: string}) => void;
-  'volume-set': ({volume}: {volume: number}) => void;
-  'track-change': ({oldTrackId, newTrackId}: {oldTrackId: string, newTrackId: string}) => void;
-  'unavailable': ({trackId}: {trackId: string}) => void;
-  'time-to-preload-next-track': ({trackId}: {trackId: string}) => void;
+  'started': ({trackId, playRequestId, positionMs, sampleOffset}: {trackId: string, playRequestId: number, positionMs: number, sampleOffset: number}) => void;
+  'stopped': ({trackId, playRequestId, sampleOffset}: {trackId: string, playRequestId: number, sampleOffset: number}) => void;
+  'loading': ({trackId, playRequestId, positionMs, sampleOffset}: {trackId: string, playRequestId: number, positionMs: number, sampleOffset: number}) => void;
+  'playing': ({trackId, playRequestId, positionMs, durationMs, replayGain, sampleOffset}: {trackId: string, playRequestId: number, positionMs: number, durationMs: number, replayGain: ISpotifyReplayGain | null, sampleOffset: number}) => void;
+  // The gain of a track whose 'playing' went out before it was known, read once the player has downloaded the file
+  'replay-gain': ({trackId, playRequestId, replayGain, sampleOffset}: {trackId: string, playRequestId: number, replayGain: ISpotifyReplayGain, sampleOffset: number}) => void;
+  'paused': ({trackId, playRequestId, positionMs, durationMs, sampleOffset}: {trackId: string, playRequestId: number, positionMs: number, durationMs: number, sampleOffset: number}) => void;
+  'end-of-track': ({trackId, playRequestId, sampleOffset}: {trackId: string, playRequestId: number, sampleOffset: number}) => void;
+  'volume-set': ({volume, sampleOffset}: {volume: number, sampleOffset: number}) => void;
+  'track-change': ({oldTrackId, newTrackId, sampleOffset}: {oldTrackId: string, newTrackId: string, sampleOffset: number}) => void;
+  'unavailable': ({trackId, playRequestId, sampleOffset}: {trackId: string, playRequestId: number, sampleOffset: number}) => void;
+  'time-to-preload-next-track': ({trackId, playRequestId, sampleOffset}: {trackId: string, playRequestId: number, sampleOffset: number}) => void;
+  // Ahead of the first audio, describes the data in the stream and the 'audio' events
+  'format': (format: ISpotifyAudioFormat) => void;
+  // Ring delivery only: a view into the ring, overwritten once the listeners have returned
+  'audio': ({data, sampleOffset}: {data: Buffer, sampleOffset: number}) => void;
+  'position': ({trackId, positionMs, durationMs, sampleOffset}: {trackId: string, positionMs: number, durationMs: number, sampleOffset: number}) => void;
+  'queue-changed': (queue: ISpotifyQueue) => void;
+  'preloaded': ({trackId, sampleOffset}: {trackId: string, sampleOffset: number}) => void;
+  'credentials': (credentials: ISpotifyCredentials) => void;
+  'session-lost': () => void;
+  'reconnecting': ({attempt, delayMs}: {attempt: number, delayMs: number}) => void;
+  'reconnected': () => void;
+  'user-connected': ({username}: {username: string}) => void;
 }